@@ -0,0 +1,212 @@
+//! A synchronous API, for use outside of async code
+//!
+//! Requests are sent from a runtime owned by this module, which runs on a dedicated thread. The
+//! functions in this module block the calling thread until the result is available; they may
+//! also be called from within an existing Tokio runtime, although that blocks its worker thread.
+//!
+//! ```rust,no_run
+//! # fn get_token() -> Result<(), gcp_auth::Error> {
+//! let provider = gcp_auth::blocking::provider()?;
+//! let scopes = &["https://www.googleapis.com/auth/cloud-platform"];
+//! let token = provider.token(scopes)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fmt;
+use std::future::Future;
+use std::sync::{mpsc, Arc, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+use http::HeaderMap;
+use tokio::runtime::{Builder, Handle};
+
+use crate::{Error, Token, TokenProvider};
+
+/// Like [`crate::provider()`], blocking until a provider is found
+pub fn provider() -> Result<Provider, Error> {
+    block_on(crate::provider()).map(Provider::new)
+}
+
+/// Like [`crate::lazy_provider()`], blocking until a provider is found
+pub fn lazy_provider() -> Result<Provider, Error> {
+    block_on(crate::lazy_provider()).map(Provider::new)
+}
+
+/// A synchronous wrapper around a [`TokenProvider`]
+#[derive(Clone)]
+pub struct Provider {
+    inner: Arc<dyn TokenProvider>,
+}
+
+impl Provider {
+    /// Wrap the given provider
+    pub fn new(provider: Arc<dyn TokenProvider>) -> Self {
+        Self { inner: provider }
+    }
+
+    /// Wrap the provider returned by the given future, blocking until it completes
+    ///
+    /// ```rust,no_run
+    /// # fn get_provider() -> Result<(), gcp_auth::Error> {
+    /// use gcp_auth::blocking::Provider;
+    /// use gcp_auth::MetadataServiceAccount;
+    ///
+    /// let provider = Provider::from_future(MetadataServiceAccount::new())?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn from_future<P, F>(future: F) -> Result<Self, Error>
+    where
+        P: TokenProvider + 'static,
+        F: Future<Output = Result<P, Error>> + Send + 'static,
+    {
+        Ok(Self::new(Arc::new(block_on(future)?)))
+    }
+
+    /// Get a valid token for the given scopes, see [`TokenProvider::token()`]
+    pub fn token(&self, scopes: &[&str]) -> Result<Arc<Token>, Error> {
+        let (inner, scopes) = (self.inner.clone(), to_owned(scopes));
+        block_on(async move { inner.token(&as_refs(&scopes)).await })
+    }
+
+    /// Get a token valid for at least `min_lifetime`, see [`TokenProvider::token_valid_for()`]
+    pub fn token_valid_for(
+        &self,
+        scopes: &[&str],
+        min_lifetime: Duration,
+    ) -> Result<Arc<Token>, Error> {
+        let (inner, scopes) = (self.inner.clone(), to_owned(scopes));
+        block_on(async move { inner.token_valid_for(&as_refs(&scopes), min_lifetime).await })
+    }
+
+    /// Drop the cached token for the given scopes, see [`TokenProvider::invalidate()`]
+    pub fn invalidate(&self, scopes: &[&str]) {
+        let (inner, scopes) = (self.inner.clone(), to_owned(scopes));
+        block_on(async move { inner.invalidate(&as_refs(&scopes)).await })
+    }
+
+    /// Get the project ID, see [`TokenProvider::project_id()`]
+    pub fn project_id(&self) -> Result<Arc<str>, Error> {
+        let inner = self.inner.clone();
+        block_on(async move { inner.project_id().await })
+    }
+
+    /// Get the headers to authenticate a request, see [`TokenProvider::headers()`]
+    pub fn headers(&self, scopes: &[&str]) -> Result<HeaderMap, Error> {
+        let (inner, scopes) = (self.inner.clone(), to_owned(scopes));
+        block_on(async move { inner.headers(&as_refs(&scopes)).await })
+    }
+
+    /// The wrapped provider
+    pub fn inner(&self) -> &Arc<dyn TokenProvider> {
+        &self.inner
+    }
+}
+
+impl From<Arc<dyn TokenProvider>> for Provider {
+    fn from(provider: Arc<dyn TokenProvider>) -> Self {
+        Self::new(provider)
+    }
+}
+
+impl fmt::Debug for Provider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Provider").finish_non_exhaustive()
+    }
+}
+
+/// Run the future on the shared runtime, blocking until it completes
+///
+/// The future is spawned rather than driven on the calling thread, so this works regardless
+/// of whether the caller is inside a runtime.
+fn block_on<F>(future: F) -> F::Output
+where
+    F: Future + Send + 'static,
+    F::Output: Send,
+{
+    let (tx, rx) = mpsc::sync_channel(1);
+    runtime().spawn(async move {
+        let _ = tx.send(future.await);
+    });
+    rx.recv()
+        .unwrap_or_else(|_| panic!("gcp_auth blocking task panicked"))
+}
+
+/// The runtime shared by all blocking providers, running on a dedicated thread
+///
+/// Tokens are fetched and refreshed in the background on this runtime, and connections are
+/// kept alive between calls, so it runs for the rest of the process.
+fn runtime() -> &'static Handle {
+    static RUNTIME: OnceLock<Handle> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        let runtime = Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("failed to build gcp_auth blocking runtime");
+        let handle = runtime.handle().clone();
+        thread::Builder::new()
+            .name("gcp_auth-blocking".to_owned())
+            .spawn(move || runtime.block_on(std::future::pending::<()>()))
+            .expect("failed to spawn gcp_auth blocking runtime thread");
+        handle
+    })
+}
+
+fn to_owned(scopes: &[&str]) -> Vec<String> {
+    scopes.iter().map(|&scope| scope.to_owned()).collect()
+}
+
+fn as_refs(scopes: &[String]) -> Vec<&str> {
+    scopes.iter().map(String::as_str).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bytes::Bytes;
+    use http::Response;
+
+    use super::*;
+    use crate::{
+        AuthorizedUserRefreshToken, ConfigDefaultCredentials, HttpClient, InMemoryTransport,
+    };
+
+    fn provider() -> Provider {
+        let client =
+            HttpClient::builder().build_with_transport(Arc::new(InMemoryTransport::new(|_| {
+                Ok(Response::new(Bytes::from_static(
+                    br#"{"access_token":"abc123","expires_in":3600}"#,
+                )))
+            })));
+        let credentials = AuthorizedUserRefreshToken::from_str(
+            r#"{
+                "client_id": "client",
+                "client_secret": "secret",
+                "quota_project_id": "project",
+                "refresh_token": "refresh",
+                "type": "authorized_user"
+            }"#,
+        )
+        .unwrap();
+        Provider::new(Arc::new(ConfigDefaultCredentials::lazy_with_credentials(
+            credentials,
+            &client,
+        )))
+    }
+
+    #[test]
+    fn test_blocking() {
+        let provider = provider();
+        assert_eq!(provider.token(&[]).unwrap().as_str(), "abc123");
+        assert_eq!(&*provider.project_id().unwrap(), "project");
+    }
+
+    #[tokio::test]
+    async fn test_blocking_in_runtime() {
+        let provider = provider();
+        assert_eq!(provider.token(&[]).unwrap().as_str(), "abc123");
+    }
+}
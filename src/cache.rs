@@ -0,0 +1,1071 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::hash::Hash;
+use std::mem;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, PoisonError, Weak};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tokio::sync::{watch, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use tracing::{debug, warn};
+
+use crate::types::{random_fraction, Token, EXPIRY_MARGIN};
+use crate::{Clock, Error, SystemClock};
+
+/// Fetches fresh tokens for a [`TokenCache`]
+#[async_trait]
+pub(crate) trait TokenSource<K>: fmt::Debug + Send + Sync + 'static {
+    /// Fetch a token for `key`, using `clock` to determine when it expires
+    async fn fetch(&self, key: &K, clock: &dyn Clock) -> Result<Arc<Token>, Error>;
+}
+
+/// A cache of tokens keyed by `K` which fetches at most one token per key at a time
+///
+/// When a token is missing or has expired, the first caller fetches a new token while
+/// subsequent callers for the same key wait for the result of that fetch, including any
+/// error. Fetches for different keys proceed independently.
+///
+/// Tokens are refreshed once they are within the expiry margin (20 seconds by default, see
+/// [`Token::has_expired()`]), but remain usable until they actually expire: while a refresh is
+/// in flight, or if it fails, the cached token is returned as long as it is still valid.
+///
+/// If [`BackgroundRefresh`] is configured, a task is spawned on first use which refreshes
+/// tokens before they expire. The task is stopped when the cache is dropped.
+#[derive(Debug)]
+pub(crate) struct TokenCache<K> {
+    shared: Arc<Shared<K>>,
+    margin: Duration,
+    background: Option<BackgroundRefresh>,
+    task: OnceLock<JoinHandle<()>>,
+}
+
+impl<K: Key> TokenCache<K> {
+    pub(crate) fn new(source: Arc<dyn TokenSource<K>>) -> Self {
+        Self {
+            shared: Arc::new(Shared::new(
+                source,
+                Arc::new(SystemClock),
+                Entries::default(),
+            )),
+            margin: EXPIRY_MARGIN,
+            background: None,
+            task: OnceLock::new(),
+        }
+    }
+
+    /// Create a cache containing the given token
+    pub(crate) fn with_token(source: Arc<dyn TokenSource<K>>, key: K, token: Arc<Token>) -> Self {
+        let this = Self::new(source);
+        let slot = Slot {
+            entry: Some(Entry::new(token, this.shared.clock.now())),
+            ..Slot::default()
+        };
+
+        this.shared.lock().slots.insert(key, slot);
+        this
+    }
+
+    /// Replace the token source, discarding all cached tokens
+    #[cfg(any(feature = "ring", feature = "aws-lc-rs"))]
+    pub(crate) fn set_source(&mut self, source: Arc<dyn TokenSource<K>>) {
+        let entries = Entries {
+            capacity: self.shared.lock().capacity,
+            ..Entries::default()
+        };
+
+        self.replace_shared(Shared::new(source, self.shared.clock.clone(), entries));
+    }
+
+    /// Use the given clock to determine when tokens expire
+    pub(crate) fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        let entries = mem::take(&mut *self.shared.lock());
+        self.replace_shared(Shared::new(self.shared.source.clone(), clock, entries));
+    }
+
+    fn replace_shared(&mut self, shared: Shared<K>) {
+        self.shared = Arc::new(shared);
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+
+    /// Limit the number of cached tokens
+    ///
+    /// When a token for a new key is requested and the cache is full, expired tokens are
+    /// evicted first, followed by the least recently used tokens.
+    #[cfg(any(feature = "ring", feature = "aws-lc-rs", test))]
+    pub(crate) fn set_capacity(&mut self, capacity: usize) {
+        self.shared.lock().capacity = Some(capacity.max(1));
+    }
+
+    /// Statistics about the use of the cache
+    #[cfg(any(feature = "ring", feature = "aws-lc-rs", test))]
+    pub(crate) fn stats(&self) -> CacheStats {
+        let entries = self.shared.lock();
+        CacheStats {
+            entries: entries
+                .slots
+                .values()
+                .filter(|slot| slot.entry.is_some())
+                .count(),
+            ..entries.stats
+        }
+    }
+
+    /// Drop the cached token for `key`, so the next call fetches a new token
+    pub(crate) fn invalidate(&self, key: &K) {
+        if let Some(slot) = self.shared.lock().slots.get_mut(key) {
+            debug!(?key, "invalidating cached token");
+            slot.entry = None;
+        }
+    }
+
+    /// Drop all cached tokens
+    pub(crate) fn invalidate_all(&self) {
+        debug!("invalidating all cached tokens");
+        for slot in self.shared.lock().slots.values_mut() {
+            slot.entry = None;
+        }
+    }
+
+    /// Refresh tokens once they expire within the given margin
+    pub(crate) fn set_expiry_margin(&mut self, margin: Duration) {
+        self.margin = margin;
+    }
+
+    /// Refresh tokens in a background task before they expire
+    pub(crate) fn set_background_refresh(&mut self, background: BackgroundRefresh) {
+        self.background = Some(background);
+    }
+
+    /// Get the cached token for `key`, fetching a new one if there is no valid token
+    ///
+    /// If a fetch for `key` is already in flight, waits for its result instead.
+    pub(crate) async fn get(&self, key: K) -> Result<Arc<Token>, Error> {
+        self.get_valid_for(key, Duration::ZERO).await
+    }
+
+    /// Get a token for `key` which is valid for at least `min_lifetime`
+    ///
+    /// Fetches a new token if the cached token expires within `min_lifetime`.
+    pub(crate) async fn get_valid_for(
+        &self,
+        key: K,
+        min_lifetime: Duration,
+    ) -> Result<Arc<Token>, Error> {
+        if let Some(background) = self.background {
+            self.task.get_or_init(|| {
+                let shared = Arc::downgrade(&self.shared);
+                tokio::spawn(refresh_in_background(shared, background))
+            });
+        }
+
+        let lifetime = Lifetime {
+            refresh: self.margin.max(min_lifetime),
+            min: min_lifetime,
+        };
+
+        let mut retried = false;
+        loop {
+            let mut pending = match self.shared.lookup(&key, lifetime) {
+                Lookup::Valid(token) => return Ok(token),
+                Lookup::Pending(pending) => pending,
+                Lookup::Fetch(tx) => return self.shared.fetch(key, tx, lifetime.min, false).await,
+            };
+
+            debug!("waiting for token fetch in progress");
+            let result = match pending.wait_for(Option::is_some).await {
+                Ok(result) => result.clone(),
+                // The fetching task was cancelled before it completed, try again
+                Err(_) => continue,
+            };
+
+            let now = self.shared.clock.now();
+            return match result {
+                // The fetch was started for a caller needing less lifetime; fetch once more
+                Some(FetchResult::Fetched(token))
+                    if token.expires_within(lifetime.min, now) && !retried =>
+                {
+                    retried = true;
+                    continue;
+                }
+                Some(FetchResult::Fetched(token)) => Ok(token),
+                Some(FetchResult::Failed(err, cached)) => {
+                    match cached.filter(|token| !token.expires_within(lifetime.min, now)) {
+                        Some(token) => Ok(token),
+                        None => Err(Error::Shared(err)),
+                    }
+                }
+                None => unreachable!(),
+            };
+        }
+    }
+}
+
+impl<K> Drop for TokenCache<K> {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.get() {
+            task.abort();
+        }
+    }
+}
+
+/// Configuration for refreshing cached tokens in the background
+///
+/// Tokens that have been used since they were fetched are refreshed once a fraction of
+/// their lifetime has passed, so callers are not delayed by the token request. A random
+/// jitter is applied to the refresh time to spread out refreshes across processes, and
+/// failed refreshes are retried with exponential backoff until the token expires.
+#[derive(Clone, Copy, Debug)]
+pub struct BackgroundRefresh {
+    fraction: f64,
+    jitter: f64,
+    min_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl BackgroundRefresh {
+    /// Refresh tokens after the given fraction of their lifetime (default 0.75)
+    ///
+    /// The fraction is clamped to the range 0.0 to 1.0.
+    pub fn with_fraction(mut self, fraction: f64) -> Self {
+        self.fraction = fraction.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Randomly move the refresh time by up to this fraction of the token lifetime (default 0.05)
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Back off between `min` and `max` after failed refreshes (default 1s to 60s)
+    pub fn with_backoff(mut self, min: Duration, max: Duration) -> Self {
+        self.min_backoff = min;
+        self.max_backoff = max.max(min);
+        self
+    }
+
+    /// When the token in `entry` should be refreshed
+    fn refresh_at(&self, entry: &Entry) -> DateTime<Utc> {
+        if let Some(retry_at) = entry.retry_at {
+            return retry_at;
+        }
+
+        let lifetime = (entry.token.expires_at() - entry.fetched_at).num_milliseconds() as f64;
+        let offset = lifetime * (self.fraction + self.jitter * entry.jitter);
+        entry.fetched_at + chrono::Duration::milliseconds(offset.clamp(0.0, lifetime) as i64)
+    }
+
+    /// How long to wait before retrying after `failures` consecutive failed refreshes
+    fn backoff(&self, failures: u32) -> Duration {
+        let backoff = self
+            .min_backoff
+            .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+            .min(self.max_backoff);
+        backoff.mul_f64(0.5 + random_fraction() / 2.0)
+    }
+}
+
+impl Default for BackgroundRefresh {
+    fn default() -> Self {
+        Self {
+            fraction: 0.75,
+            jitter: 0.05,
+            min_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+async fn refresh_in_background<K: Key>(shared: Weak<Shared<K>>, background: BackgroundRefresh) {
+    loop {
+        let Some(strong) = shared.upgrade() else {
+            return;
+        };
+
+        // Register for updates before looking for the next token to avoid missing any
+        let notify = strong.updated.clone();
+        let updated = notify.notified();
+        let next = strong.next_refresh(&background);
+        let clock = strong.clock.clone();
+        drop(strong);
+
+        let Some((key, refresh_at)) = next else {
+            updated.await;
+            continue;
+        };
+
+        let delay = (refresh_at - clock.now()).to_std().unwrap_or_default();
+        if timeout(delay, updated).await.is_ok() {
+            // Tokens were updated, which may change which token should be refreshed next
+            continue;
+        }
+
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+
+        let Some(tx) = shared.lookup_refresh(&key) else {
+            continue;
+        };
+
+        debug!(?key, "refreshing token in background");
+        // Errors are logged by the fetch, which returns the cached token if it is still valid
+        let _ = shared.fetch(key.clone(), tx, Duration::ZERO, true).await;
+        let mut entries = shared.lock();
+        let Some(entry) = entries
+            .slots
+            .get_mut(&key)
+            .and_then(|slot| slot.entry.as_mut())
+        else {
+            continue;
+        };
+
+        if entry.failures > 0 {
+            let backoff = background.backoff(entry.failures);
+            debug!(?backoff, "retrying background token refresh after backoff");
+            entry.retry_at = Some(clock.now() + backoff);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Shared<K> {
+    source: Arc<dyn TokenSource<K>>,
+    clock: Arc<dyn Clock>,
+    entries: Mutex<Entries<K>>,
+    updated: Arc<Notify>,
+}
+
+impl<K: Key> Shared<K> {
+    fn new(source: Arc<dyn TokenSource<K>>, clock: Arc<dyn Clock>, entries: Entries<K>) -> Self {
+        Self {
+            source,
+            clock,
+            entries: Mutex::new(entries),
+            updated: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Find a valid token for `key` or the fetch in flight, or else register a new fetch
+    fn lookup(&self, key: &K, lifetime: Lifetime) -> Lookup {
+        let now = self.clock.now();
+        let mut entries = self.lock();
+        let lookup = self.lookup_slot(entries.slot(key, now), lifetime, now);
+        match lookup {
+            Lookup::Valid(_) => entries.stats.hits += 1,
+            Lookup::Pending(_) | Lookup::Fetch(_) => entries.stats.misses += 1,
+        }
+
+        lookup
+    }
+
+    fn lookup_slot(&self, slot: &mut Slot, lifetime: Lifetime, now: DateTime<Utc>) -> Lookup {
+        if let Some(entry) = &mut slot.entry {
+            // While a fetch is in flight, keep using the cached token until it actually expires
+            let usable = match slot.pending {
+                Some(_) => !entry.token.expires_within(lifetime.min, now),
+                None => !entry.token.expires_within(lifetime.refresh, now),
+            };
+
+            if usable {
+                if !entry.used {
+                    entry.used = true;
+                    self.updated.notify_waiters();
+                }
+
+                return Lookup::Valid(entry.token.clone());
+            }
+        }
+
+        match &slot.pending {
+            Some(pending) => Lookup::Pending(pending.clone()),
+            None => {
+                let (tx, rx) = watch::channel(None);
+                slot.pending = Some(rx);
+                Lookup::Fetch(tx)
+            }
+        }
+    }
+
+    /// Register a background refresh for `key`, unless a fetch is already in flight
+    fn lookup_refresh(&self, key: &K) -> Option<watch::Sender<Option<FetchResult>>> {
+        let mut entries = self.lock();
+        let slot = entries.slots.get_mut(key)?;
+        if slot.pending.is_some() {
+            return None;
+        }
+
+        let (tx, rx) = watch::channel(None);
+        slot.pending = Some(rx);
+        Some(tx)
+    }
+
+    /// Find the used token that should be refreshed first
+    fn next_refresh(&self, background: &BackgroundRefresh) -> Option<(K, DateTime<Utc>)> {
+        let now = self.clock.now();
+        self.lock()
+            .slots
+            .iter()
+            .filter(|(_, slot)| slot.pending.is_none())
+            .filter_map(|(key, slot)| {
+                let entry = slot.entry.as_ref()?;
+                match entry.used && !entry.token.expires_within(Duration::ZERO, now) {
+                    true => Some((key, background.refresh_at(entry))),
+                    false => None,
+                }
+            })
+            .min_by_key(|(_, refresh_at)| *refresh_at)
+            .map(|(key, refresh_at)| (key.clone(), refresh_at))
+    }
+
+    /// Fetch a token for `key`, sharing the result through `tx`
+    ///
+    /// If the fetch fails, the cached token is returned if it is valid for `min_lifetime`.
+    async fn fetch(
+        &self,
+        key: K,
+        tx: watch::Sender<Option<FetchResult>>,
+        min_lifetime: Duration,
+        background: bool,
+    ) -> Result<Arc<Token>, Error> {
+        let fetch = self.source.fetch(&key, &*self.clock);
+        let pending = PendingFetch {
+            shared: self,
+            key: key.clone(),
+            tx: Some(tx),
+            min_lifetime,
+            background,
+        };
+
+        pending.run(fetch).await
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Entries<K>> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A fetch in flight, shared with other callers through a [`watch`] channel
+///
+/// If the fetching future is dropped before completing, the pending state is removed so
+/// that waiting callers can retry.
+struct PendingFetch<'a, K: Key> {
+    shared: &'a Shared<K>,
+    key: K,
+    tx: Option<watch::Sender<Option<FetchResult>>>,
+    min_lifetime: Duration,
+    background: bool,
+}
+
+impl<K: Key> PendingFetch<'_, K> {
+    async fn run(
+        mut self,
+        fetch: impl Future<Output = Result<Arc<Token>, Error>>,
+    ) -> Result<Arc<Token>, Error> {
+        let result = fetch.await;
+        let tx = self.tx.take().unwrap();
+        let cached = self.complete(&result);
+
+        let err = match result {
+            Ok(token) => {
+                tx.send_replace(Some(FetchResult::Fetched(token.clone())));
+                return Ok(token);
+            }
+            // Wrapped even if nobody else is waiting, so all callers see the same variant
+            Err(err) => Arc::new(err),
+        };
+
+        // Waiting callers check the cached token against their own minimum lifetime
+        tx.send_replace(Some(FetchResult::Failed(err.clone(), cached.clone())));
+        let now = self.shared.clock.now();
+        match cached.filter(|token| !token.expires_within(self.min_lifetime, now)) {
+            Some(token) => {
+                warn!(
+                    ?err,
+                    expires_at = %token.expires_at(),
+                    "failed to refresh token, using cached token until it expires"
+                );
+                Ok(token)
+            }
+            None => Err(Error::Shared(err)),
+        }
+    }
+
+    /// Store the result of the fetch, returning the cached token if the fetch failed
+    /// and it has not expired yet
+    fn complete(&self, result: &Result<Arc<Token>, Error>) -> Option<Arc<Token>> {
+        let now = self.shared.clock.now();
+        let mut entries = self.shared.lock();
+        let slot = entries.slots.entry(self.key.clone()).or_default();
+        slot.pending = None;
+        match result {
+            Ok(token) => {
+                // Tokens refreshed in the background only count as used once they are requested
+                let mut entry = Entry::new(token.clone(), now);
+                entry.used = !self.background;
+                slot.entry = Some(entry);
+            }
+            Err(_) => {
+                if let Some(entry) = &mut slot.entry {
+                    entry.failures += 1;
+                }
+            }
+        }
+
+        self.shared.updated.notify_waiters();
+        let entry = slot.entry.as_mut().filter(|_| result.is_err())?;
+        match entry.token.expires_within(Duration::ZERO, now) {
+            true => None,
+            false => {
+                entry.used = true;
+                Some(entry.token.clone())
+            }
+        }
+    }
+}
+
+impl<K: Key> Drop for PendingFetch<'_, K> {
+    fn drop(&mut self) {
+        let Some(tx) = &self.tx else {
+            return;
+        };
+
+        let mut entries = self.shared.lock();
+        if let Some(slot) = entries.slots.get_mut(&self.key) {
+            if let Some(pending) = &slot.pending {
+                if pending.same_channel(&tx.subscribe()) {
+                    slot.pending = None;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Lifetime {
+    /// Refresh tokens that expire within this duration
+    refresh: Duration,
+    /// Never return tokens that expire within this duration
+    min: Duration,
+}
+
+enum Lookup {
+    Valid(Arc<Token>),
+    Pending(watch::Receiver<Option<FetchResult>>),
+    Fetch(watch::Sender<Option<FetchResult>>),
+}
+
+/// Statistics about the use of a token cache
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CacheStats {
+    /// Number of requests served from the cache
+    pub hits: u64,
+    /// Number of requests which required a token to be fetched
+    pub misses: u64,
+    /// Number of tokens evicted to stay within the capacity
+    pub evictions: u64,
+    /// Number of tokens currently in the cache
+    pub entries: usize,
+}
+
+#[derive(Debug)]
+struct Entries<K> {
+    slots: HashMap<K, Slot>,
+    capacity: Option<usize>,
+    /// Incremented on every lookup to track the least recently used slot
+    tick: u64,
+    stats: CacheStats,
+}
+
+impl<K: Key> Entries<K> {
+    /// Get the slot for `key`, making room for it if the cache is full
+    fn slot(&mut self, key: &K, now: DateTime<Utc>) -> &mut Slot {
+        self.tick += 1;
+        if !self.slots.contains_key(key) {
+            self.evict(now);
+        }
+
+        let slot = self.slots.entry(key.clone()).or_default();
+        slot.last_used = self.tick;
+        slot
+    }
+
+    /// Evict expired and least recently used tokens until there is room for another token
+    fn evict(&mut self, now: DateTime<Utc>) {
+        let Some(capacity) = self.capacity else {
+            return;
+        };
+
+        if self.slots.len() < capacity {
+            return;
+        }
+
+        // Slots with a fetch in flight are never evicted
+        let before = self.slots.len();
+        self.slots
+            .retain(|_, slot| match (&slot.entry, &slot.pending) {
+                (_, Some(_)) => true,
+                (Some(entry), None) => !entry.token.expires_within(Duration::ZERO, now),
+                (None, None) => false,
+            });
+        self.stats.evictions += (before - self.slots.len()) as u64;
+
+        while self.slots.len() >= capacity {
+            let lru = self
+                .slots
+                .iter()
+                .filter(|(_, slot)| slot.pending.is_none())
+                .min_by_key(|(_, slot)| slot.last_used)
+                .map(|(key, _)| key.clone());
+
+            let Some(key) = lru else {
+                break;
+            };
+
+            debug!(?key, "evicting least recently used token");
+            self.slots.remove(&key);
+            self.stats.evictions += 1;
+        }
+    }
+}
+
+impl<K> Default for Entries<K> {
+    fn default() -> Self {
+        Self {
+            slots: HashMap::new(),
+            capacity: None,
+            tick: 0,
+            stats: CacheStats::default(),
+        }
+    }
+}
+
+/// The cached token for a key, if any, and the fetch in flight
+#[derive(Debug, Default)]
+struct Slot {
+    entry: Option<Entry>,
+    pending: Option<watch::Receiver<Option<FetchResult>>>,
+    last_used: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    token: Arc<Token>,
+    fetched_at: DateTime<Utc>,
+    /// Whether the token has been handed out since it was fetched
+    used: bool,
+    /// Random value in the range -1.0 to 1.0 to scale the refresh jitter
+    jitter: f64,
+    /// Consecutive failed refreshes
+    failures: u32,
+    retry_at: Option<DateTime<Utc>>,
+}
+
+impl Entry {
+    fn new(token: Arc<Token>, now: DateTime<Utc>) -> Self {
+        Self {
+            token,
+            fetched_at: now,
+            used: true,
+            jitter: random_fraction() * 2.0 - 1.0,
+            failures: 0,
+            retry_at: None,
+        }
+    }
+}
+
+/// The outcome of a fetch, shared with the callers waiting for it
+#[derive(Clone, Debug)]
+enum FetchResult {
+    /// A freshly fetched token
+    Fetched(Arc<Token>),
+    /// The fetch failed; the previously cached token is included if it has not expired
+    Failed(Arc<Error>, Option<Arc<Token>>),
+}
+
+/// Requirements for cache keys
+pub(crate) trait Key: Clone + fmt::Debug + Eq + Hash + Send + Sync + 'static {}
+
+impl<K: Clone + fmt::Debug + Eq + Hash + Send + Sync + 'static> Key for K {}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use tokio::time::sleep;
+
+    use super::*;
+    use crate::ManualClock;
+
+    #[derive(Debug, Default)]
+    struct TestSource {
+        fetches: AtomicUsize,
+        fail: AtomicBool,
+        /// Block fetches for the key `"a"` forever
+        hang: AtomicBool,
+    }
+
+    #[async_trait]
+    impl TokenSource<&'static str> for TestSource {
+        async fn fetch(&self, key: &&'static str, clock: &dyn Clock) -> Result<Arc<Token>, Error> {
+            let n = self.fetches.fetch_add(1, Ordering::SeqCst) + 1;
+            if *key == "a" && self.hang.load(Ordering::SeqCst) {
+                std::future::pending::<()>().await;
+            }
+
+            sleep(Duration::from_millis(20)).await;
+            match self.fail.load(Ordering::SeqCst) {
+                true => Err(Error::Str("token request failed")),
+                false => Ok(Arc::new(Token::new(
+                    format!("{key}-{n}"),
+                    clock.now() + Duration::from_secs(3600),
+                ))),
+            }
+        }
+    }
+
+    fn token(access_token: &str, expires_in: Duration) -> Arc<Token> {
+        Arc::new(Token::from_string(access_token.to_owned(), expires_in))
+    }
+
+    async fn concurrent_gets(
+        cache: &Arc<TokenCache<&'static str>>,
+        n: usize,
+    ) -> Vec<Result<Arc<Token>, Error>> {
+        let mut tasks = Vec::new();
+        for _ in 0..n {
+            let cache = cache.clone();
+            tasks.push(tokio::spawn(async move { cache.get("a").await }));
+        }
+
+        let mut results = Vec::new();
+        for task in tasks {
+            results.push(task.await.unwrap());
+        }
+        results
+    }
+
+    #[tokio::test]
+    async fn test_single_flight() {
+        let source = Arc::new(TestSource::default());
+        let cache = Arc::new(TokenCache::new(source.clone()));
+
+        for result in concurrent_gets(&cache, 50).await {
+            assert_eq!(result.unwrap().as_str(), "a-1");
+        }
+        assert_eq!(source.fetches.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_shared_error() {
+        let source = Arc::new(TestSource::default());
+        source.fail.store(true, Ordering::SeqCst);
+        let cache = Arc::new(TokenCache::new(source.clone()));
+
+        for result in concurrent_gets(&cache, 10).await {
+            let err = result.unwrap_err();
+            assert!(matches!(err, Error::Shared(_)), "{err:?}");
+            assert_eq!(err.to_string(), "token request failed");
+        }
+        assert_eq!(source.fetches.load(Ordering::SeqCst), 1);
+
+        // Errors are not cached
+        source.fail.store(false, Ordering::SeqCst);
+        assert_eq!(cache.get("a").await.unwrap().as_str(), "a-2");
+    }
+
+    #[tokio::test]
+    async fn test_expired() {
+        let source = Arc::new(TestSource::default());
+        let cache = TokenCache::with_token(source, "a", token("old", Duration::from_secs(10)));
+        assert_eq!(cache.get("a").await.unwrap().as_str(), "a-1");
+    }
+
+    #[tokio::test]
+    async fn test_invalidate() {
+        let source = Arc::new(TestSource::default());
+        let cache = TokenCache::new(source.clone());
+        assert_eq!(cache.get("a").await.unwrap().as_str(), "a-1");
+        assert_eq!(cache.get("b").await.unwrap().as_str(), "b-2");
+
+        cache.invalidate(&"a");
+        assert_eq!(cache.get("a").await.unwrap().as_str(), "a-3");
+        assert_eq!(cache.get("b").await.unwrap().as_str(), "b-2");
+
+        cache.invalidate_all();
+        assert_eq!(cache.get("a").await.unwrap().as_str(), "a-4");
+        assert_eq!(cache.get("b").await.unwrap().as_str(), "b-5");
+    }
+
+    #[tokio::test]
+    async fn test_clock() {
+        let source = Arc::new(TestSource::default());
+        let clock = Arc::new(ManualClock::default());
+        let mut cache = TokenCache::new(source.clone());
+        cache.set_clock(clock.clone());
+        assert_eq!(cache.get("a").await.unwrap().as_str(), "a-1");
+
+        // Still valid until it expires within the margin
+        clock.advance(Duration::from_secs(3600) - EXPIRY_MARGIN - Duration::from_secs(1));
+        assert_eq!(cache.get("a").await.unwrap().as_str(), "a-1");
+        assert_eq!(
+            cache
+                .get_valid_for("a", Duration::from_secs(60))
+                .await
+                .unwrap()
+                .as_str(),
+            "a-2"
+        );
+
+        clock.advance(Duration::from_secs(3600) - EXPIRY_MARGIN - Duration::from_secs(1));
+        assert_eq!(cache.get("a").await.unwrap().as_str(), "a-2");
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(cache.get("a").await.unwrap().as_str(), "a-3");
+    }
+
+    #[tokio::test]
+    async fn test_min_lifetime() {
+        let source = Arc::new(TestSource::default());
+        let cache = TokenCache::with_token(
+            source.clone(),
+            "a",
+            token("short", Duration::from_secs(300)),
+        );
+
+        let token = cache.get("a").await.unwrap();
+        assert_eq!(token.as_str(), "short");
+        let token = cache.get_valid_for("a", Duration::from_secs(240)).await;
+        assert_eq!(token.unwrap().as_str(), "short");
+
+        let token = cache.get_valid_for("a", Duration::from_secs(600)).await;
+        assert_eq!(token.unwrap().as_str(), "a-1");
+        assert_eq!(cache.get("a").await.unwrap().as_str(), "a-1");
+    }
+
+    #[tokio::test]
+    async fn test_expiry_margin() {
+        let source = Arc::new(TestSource::default());
+        let mut cache = TokenCache::with_token(
+            source.clone(),
+            "a",
+            token("short", Duration::from_secs(300)),
+        );
+
+        cache.set_expiry_margin(Duration::from_secs(600));
+        assert_eq!(cache.get("a").await.unwrap().as_str(), "a-1");
+    }
+
+    #[tokio::test]
+    async fn test_stale_while_revalidate() {
+        let source = Arc::new(TestSource::default());
+        source.fail.store(true, Ordering::SeqCst);
+
+        // Within the refresh margin, but still valid
+        let cache =
+            TokenCache::with_token(source.clone(), "b", token("stale", Duration::from_secs(10)));
+        assert_eq!(cache.get("b").await.unwrap().as_str(), "stale");
+        assert_eq!(source.fetches.load(Ordering::SeqCst), 1);
+
+        // Callers arriving while a refresh is in flight do not wait for it
+        source.hang.store(true, Ordering::SeqCst);
+        let cache = Arc::new(TokenCache::with_token(
+            source.clone(),
+            "a",
+            token("stale", Duration::from_secs(10)),
+        ));
+        let hanging = {
+            let cache = cache.clone();
+            tokio::spawn(async move { cache.get("a").await })
+        };
+
+        while source.fetches.load(Ordering::SeqCst) == 1 {
+            sleep(Duration::from_millis(1)).await;
+        }
+
+        let token = timeout(Duration::from_secs(1), cache.get("a")).await;
+        assert_eq!(token.unwrap().unwrap().as_str(), "stale");
+        hanging.abort();
+    }
+
+    #[tokio::test]
+    async fn test_waiter_min_lifetime() {
+        let source = Arc::new(TestSource::default());
+        source.fail.store(true, Ordering::SeqCst);
+        let cache = TokenCache::with_token(
+            source.clone(),
+            "a",
+            token("expiring", Duration::from_secs(9)),
+        );
+
+        // The second caller waits for the fetch started by the first one
+        let (first, second) = tokio::join!(
+            cache.get("a"),
+            cache.get_valid_for("a", Duration::from_secs(600))
+        );
+        assert_eq!(first.unwrap().as_str(), "expiring");
+        assert!(second.is_err());
+        assert_eq!(source.fetches.load(Ordering::SeqCst), 1);
+
+        source.fail.store(false, Ordering::SeqCst);
+        let (first, second) = tokio::join!(
+            cache.get("a"),
+            cache.get_valid_for("a", Duration::from_secs(600))
+        );
+        assert_eq!(first.unwrap().as_str(), "a-2");
+        assert_eq!(second.unwrap().as_str(), "a-2");
+    }
+
+    #[tokio::test]
+    async fn test_expired_refresh_error() {
+        let source = Arc::new(TestSource::default());
+        source.fail.store(true, Ordering::SeqCst);
+        let cache = TokenCache::with_token(source, "a", token("expired", Duration::ZERO));
+        let err = cache.get("a").await.unwrap_err();
+        assert!(matches!(err, Error::Shared(_)), "{err:?}");
+        assert_eq!(err.to_string(), "token request failed");
+    }
+
+    #[tokio::test]
+    async fn test_independent_keys() {
+        let source = Arc::new(TestSource::default());
+        source.hang.store(true, Ordering::SeqCst);
+        let cache = Arc::new(TokenCache::new(source.clone()));
+
+        let hanging = {
+            let cache = cache.clone();
+            tokio::spawn(async move { cache.get("a").await })
+        };
+
+        while source.fetches.load(Ordering::SeqCst) == 0 {
+            sleep(Duration::from_millis(1)).await;
+        }
+
+        // While the fetch for "a" is blocked, a fetch for "b" completes
+        let token = timeout(Duration::from_secs(1), cache.get("b")).await;
+        assert_eq!(token.unwrap().unwrap().as_str(), "b-2");
+        hanging.abort();
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_fetch() {
+        let source = Arc::new(TestSource::default());
+        source.hang.store(true, Ordering::SeqCst);
+        let cache = TokenCache::new(source.clone());
+        assert!(timeout(Duration::from_millis(10), cache.get("a"))
+            .await
+            .is_err());
+
+        source.hang.store(false, Ordering::SeqCst);
+        assert_eq!(cache.get("a").await.unwrap().as_str(), "a-2");
+    }
+
+    #[tokio::test]
+    async fn test_background_refresh() {
+        let source = Arc::new(TestSource::default());
+        let mut cache = TokenCache::with_token(
+            source.clone(),
+            "a",
+            token("initial", Duration::from_secs(3600)),
+        );
+        cache.set_background_refresh(
+            BackgroundRefresh::default()
+                .with_fraction(0.0)
+                .with_jitter(0.0),
+        );
+
+        assert_eq!(cache.get("a").await.unwrap().as_str(), "initial");
+        for _ in 0..100 {
+            if source.fetches.load(Ordering::SeqCst) > 0 {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        sleep(Duration::from_millis(50)).await;
+        assert_eq!(cache.get("a").await.unwrap().as_str(), "a-1");
+
+        // The refreshed token is only refreshed again after it has been used
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(source.fetches.load(Ordering::SeqCst), 2);
+
+        // Dropping the cache stops the background task, releasing the source
+        drop(cache);
+        for _ in 0..100 {
+            if Arc::strong_count(&source) == 1 {
+                return;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        panic!("background task was not stopped");
+    }
+
+    #[tokio::test]
+    async fn test_capacity() {
+        let source = Arc::new(TestSource::default());
+        let mut cache = TokenCache::with_token(source.clone(), "a", token("a-0", Duration::ZERO));
+        cache.set_capacity(2);
+
+        // The expired token for "a" is evicted first
+        cache.get("b").await.unwrap();
+        cache.get("c").await.unwrap();
+        assert_eq!(cache.stats().evictions, 1);
+
+        // Then the least recently used token, which is "c" after "b" was used again
+        cache.get("b").await.unwrap();
+        cache.get("d").await.unwrap();
+        let stats = cache.stats();
+        assert_eq!(stats.evictions, 2);
+        assert_eq!(stats.entries, 2);
+        assert_eq!(cache.get("b").await.unwrap().as_str(), "b-1");
+        assert_eq!(cache.get("c").await.unwrap().as_str(), "c-4");
+        assert_eq!(source.fetches.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn test_stats() {
+        let source = Arc::new(TestSource::default());
+        let cache = TokenCache::new(source);
+        cache.get("a").await.unwrap();
+        cache.get("a").await.unwrap();
+        cache.get("b").await.unwrap();
+
+        let stats = cache.stats();
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 2);
+        assert_eq!(stats.evictions, 0);
+        assert_eq!(stats.entries, 2);
+    }
+
+    #[test]
+    fn test_refresh_at() {
+        let background = BackgroundRefresh::default().with_jitter(0.1);
+        let mut entry = Entry::new(token("abc123", Duration::from_secs(1000)), Utc::now());
+        for jitter in [-1.0, 0.0, 1.0] {
+            entry.jitter = jitter;
+            let offset = background.refresh_at(&entry) - entry.fetched_at;
+            let expected = 750 + (jitter * 100.0) as i64;
+            assert!((offset.num_seconds() - expected).abs() <= 1);
+        }
+
+        let retry_at = Utc::now() + Duration::from_secs(5);
+        entry.retry_at = Some(retry_at);
+        assert_eq!(background.refresh_at(&entry), retry_at);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let background = BackgroundRefresh::default();
+        for (failures, max) in [(1, 1), (2, 2), (3, 4), (7, 60), (100, 60)] {
+            let backoff = background.backoff(failures);
+            assert!(backoff <= Duration::from_secs(max));
+            assert!(backoff >= Duration::from_secs(max) / 2);
+        }
+    }
+}
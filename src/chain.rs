@@ -0,0 +1,823 @@
+use std::fmt;
+use std::future::{poll_fn, Future};
+use std::io;
+use std::iter;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::Duration;
+
+use tokio::time::Instant;
+use tracing::{debug, instrument, Level};
+
+use crate::config_default_credentials::credentials_path;
+use crate::gcloud_authorized_user::GCLOUD_CMD;
+use crate::metadata_service_account;
+#[cfg(any(feature = "ring", feature = "aws-lc-rs"))]
+use crate::types::ENV_CREDENTIALS;
+#[cfg(any(feature = "ring", feature = "aws-lc-rs"))]
+use crate::CustomServiceAccount;
+use crate::{
+    BackgroundRefresh, Clock, ConfigDefaultCredentials, Error, GCloudAuthorizedUser, HttpClient,
+    MetadataServiceAccount, TokenProvider,
+};
+
+/// Builder for a chain of token sources, selecting the first one in order that succeeds
+///
+/// All sources are probed concurrently, so that slow sources (like the metadata server or
+/// the `gcloud` CLI) don't delay each other. [`ProviderChain::default()`] tries the built-in
+/// sources in the order documented for [`provider()`](crate::provider()). Start from
+/// [`ProviderChain::new()`] to pick the sources and their order, and add custom sources with
+/// [`ProviderChain::with_provider()`]. All sources share one [`HttpClient`], and the built-in
+/// providers share the configured expiry margin, background refresh and clock.
+///
+/// ```rust,no_run
+/// # async fn get_provider() -> Result<(), gcp_auth::Error> {
+/// use gcp_auth::{BuiltinSource, ProviderChain};
+///
+/// let report = ProviderChain::new()
+///     .with_source(BuiltinSource::MetadataServiceAccount)
+///     .with_source(BuiltinSource::ConfigDefaultCredentials)
+///     .resolve()
+///     .await;
+/// for attempt in report.attempts() {
+///     println!("{}: {:?}", attempt.source(), attempt.outcome());
+/// }
+/// let provider = report.into_provider()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct ProviderChain {
+    steps: Vec<Step>,
+    client: Option<HttpClient>,
+    expiry_margin: Option<Duration>,
+    background_refresh: Option<BackgroundRefresh>,
+    clock: Option<Arc<dyn Clock>>,
+    lazy: bool,
+}
+
+impl ProviderChain {
+    /// Create an empty chain
+    pub fn new() -> Self {
+        Self {
+            steps: Vec::new(),
+            ..Self::default_sources()
+        }
+    }
+
+    /// Try the given built-in source after the sources added so far
+    ///
+    /// If the source was already part of the chain, it is moved to the end.
+    pub fn with_source(mut self, source: BuiltinSource) -> Self {
+        self = self.without_source(source);
+        self.steps.push(Step::Builtin(source));
+        self
+    }
+
+    /// Don't try the given built-in source
+    pub fn without_source(mut self, source: BuiltinSource) -> Self {
+        self.steps
+            .retain(|step| !matches!(step, Step::Builtin(s) if *s == source));
+        self
+    }
+
+    /// Try a custom source after the sources added so far
+    ///
+    /// The factory is called with the chain's [`HttpClient`]. It returns `Ok(None)` if the
+    /// source is not available (it is then reported as skipped), or an error if it failed.
+    pub fn with_provider<F, Fut>(mut self, name: &'static str, factory: F) -> Self
+    where
+        F: Fn(HttpClient) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Option<Arc<dyn TokenProvider>>, Error>> + Send + 'static,
+    {
+        self.steps.push(Step::Custom {
+            name,
+            factory: Box::new(move |client| Box::pin(factory(client))),
+        });
+        self
+    }
+
+    /// Send all requests with the given [`HttpClient`] (by default, a new client is created)
+    pub fn with_client(mut self, client: HttpClient) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Refresh tokens from built-in providers once they expire within the given margin
+    pub fn with_expiry_margin(mut self, margin: Duration) -> Self {
+        self.expiry_margin = Some(margin);
+        self
+    }
+
+    /// Refresh tokens from built-in providers in the background before they expire
+    pub fn with_background_refresh(mut self, refresh: BackgroundRefresh) -> Self {
+        self.background_refresh = Some(refresh);
+        self
+    }
+
+    /// Use the given clock to determine when tokens from built-in providers expire
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Detect built-in sources without requesting a token (disabled by default)
+    ///
+    /// Built-in sources are selected if they are configured, using the lazy constructors like
+    /// [`ConfigDefaultCredentials::lazy()`]. The first token is only requested when it is
+    /// needed, so a temporary outage of the token endpoint doesn't prevent finding a provider.
+    /// The metadata server is still contacted to check that it is available, requesting only
+    /// the project ID.
+    pub fn with_lazy_detection(mut self, lazy: bool) -> Self {
+        self.lazy = lazy;
+        self
+    }
+
+    /// Find a provider, returning it as an error if none of the sources succeeded
+    pub async fn build(self) -> Result<Arc<dyn TokenProvider>, Error> {
+        self.resolve().await.into_provider()
+    }
+
+    /// Try the sources concurrently, reporting the outcome for each source
+    ///
+    /// The first source in the chain's order which succeeds is selected, even if sources
+    /// after it finished earlier. Once the outcome is decided, probes of sources after the
+    /// selected one are cancelled. A failure to load the `GOOGLE_APPLICATION_CREDENTIALS`
+    /// credentials ends the search, since those credentials were configured explicitly.
+    #[instrument(level = Level::DEBUG, skip(self))]
+    pub async fn resolve(self) -> ChainReport {
+        let mut report = ChainReport {
+            attempts: Vec::with_capacity(self.steps.len()),
+            provider: None,
+            error: None,
+        };
+
+        let client = match self.client.clone() {
+            Some(client) => client,
+            None => match HttpClient::new() {
+                Ok(client) => client,
+                Err(err) => {
+                    report.error = Some(err);
+                    return report;
+                }
+            },
+        };
+
+        let start = Instant::now();
+        let mut probes = self
+            .steps
+            .iter()
+            .map(|step| Some(Box::pin(self.probe(step, &client))))
+            .collect::<Vec<_>>();
+        let mut results = iter::repeat_with(|| None)
+            .take(probes.len())
+            .collect::<Vec<_>>();
+
+        // Poll all probes until the outcome of a prefix of the chain decides the search
+        let mut next = 0;
+        let end = poll_fn(|cx| {
+            for (probe, result) in probes.iter_mut().zip(&mut results).skip(next) {
+                let Some(future) = probe else {
+                    continue;
+                };
+                if let Poll::Ready(probe_result) = future.as_mut().poll(cx) {
+                    *result = Some((probe_result, start.elapsed()));
+                    *probe = None;
+                }
+            }
+
+            while let Some(Some(((result, _), _))) = results.get(next) {
+                let step = &self.steps[next];
+                next += 1;
+                let done = match result {
+                    Ok(_) => true,
+                    Err(AttemptOutcome::Failed(_)) => {
+                        *step == Step::Builtin(BuiltinSource::CustomServiceAccount)
+                    }
+                    Err(_) => false,
+                };
+                if done {
+                    return Poll::Ready(next);
+                }
+            }
+
+            match next == results.len() {
+                true => Poll::Ready(next),
+                false => Poll::Pending,
+            }
+        })
+        .await;
+
+        // Cancel the remaining probes
+        drop(probes);
+        let cancelled_after = start.elapsed();
+
+        for (i, (step, result)) in self.steps.iter().zip(results).enumerate() {
+            let source = step.name();
+            let (outcome, checked, duration) = match result {
+                Some(((result, checked), duration)) if i < end => {
+                    let outcome = match result {
+                        Ok(provider) => {
+                            debug!(source, ?duration, "using token provider");
+                            report.provider = Some(provider);
+                            AttemptOutcome::Selected
+                        }
+                        Err(outcome) => {
+                            debug!(source, ?duration, ?outcome, "token source not used");
+                            outcome
+                        }
+                    };
+                    (outcome, checked, duration)
+                }
+                Some(((_, checked), duration)) => (AttemptOutcome::Cancelled, checked, duration),
+                None => (AttemptOutcome::Cancelled, Vec::new(), cancelled_after),
+            };
+
+            report.attempts.push(Attempt {
+                source,
+                outcome,
+                checked,
+                duration,
+            });
+        }
+
+        report
+    }
+
+    /// Try a single source, returning the paths and URLs checked along with the outcome
+    async fn probe(
+        &self,
+        step: &Step,
+        client: &HttpClient,
+    ) -> (Result<Arc<dyn TokenProvider>, AttemptOutcome>, Vec<String>) {
+        let mut checked = Vec::new();
+        let result = match step {
+            Step::Builtin(source) => self.builtin(*source, client, &mut checked).await,
+            Step::Custom { factory, .. } => match factory(client.clone()).await {
+                Ok(Some(provider)) => Ok(provider),
+                Ok(None) => Err(AttemptOutcome::Skipped("not available")),
+                Err(err) => Err(AttemptOutcome::Failed(err)),
+            },
+        };
+        (result, checked)
+    }
+
+    /// Try a built-in source, recording the paths and URLs checked
+    async fn builtin(
+        &self,
+        source: BuiltinSource,
+        client: &HttpClient,
+        checked: &mut Vec<String>,
+    ) -> Result<Arc<dyn TokenProvider>, AttemptOutcome> {
+        match source {
+            #[cfg(any(feature = "ring", feature = "aws-lc-rs"))]
+            BuiltinSource::CustomServiceAccount => {
+                let Some(path) = std::env::var_os(ENV_CREDENTIALS) else {
+                    return Err(AttemptOutcome::Skipped(
+                        "GOOGLE_APPLICATION_CREDENTIALS is not set",
+                    ));
+                };
+                checked.push(path.to_string_lossy().into_owned());
+                match CustomServiceAccount::from_env_with_client(client) {
+                    Ok(Some(provider)) => Ok(Arc::new(self.configure(provider))),
+                    Ok(None) => Err(AttemptOutcome::Skipped(
+                        "GOOGLE_APPLICATION_CREDENTIALS is not set",
+                    )),
+                    Err(err) => Err(AttemptOutcome::Failed(err)),
+                }
+            }
+            #[cfg(not(any(feature = "ring", feature = "aws-lc-rs")))]
+            BuiltinSource::CustomServiceAccount => Err(AttemptOutcome::Skipped(
+                "requires the `ring` or `aws-lc-rs` feature",
+            )),
+            BuiltinSource::ConfigDefaultCredentials => {
+                let path = credentials_path().map_err(AttemptOutcome::Failed)?;
+                checked.push(path.to_string_lossy().into_owned());
+                let result = match self.lazy {
+                    true => ConfigDefaultCredentials::lazy(client),
+                    false => ConfigDefaultCredentials::with_client(client).await,
+                };
+                match result {
+                    Ok(provider) => Ok(Arc::new(self.configure(provider))),
+                    Err(err) if is_not_found(&err) => Err(AttemptOutcome::Skipped(
+                        "no application default credentials file",
+                    )),
+                    Err(err) => Err(AttemptOutcome::Failed(err)),
+                }
+            }
+            BuiltinSource::MetadataServiceAccount => {
+                checked.push(metadata_service_account::DEFAULT_TOKEN_GCP_URI.to_owned());
+                let result = match self.lazy {
+                    true => {
+                        let provider = MetadataServiceAccount::lazy(client);
+                        provider.project_id().await.map(|_| provider)
+                    }
+                    false => MetadataServiceAccount::with_client(client).await,
+                };
+                match result {
+                    Ok(provider) => Ok(Arc::new(self.configure(provider))),
+                    Err(err) => Err(AttemptOutcome::Failed(err)),
+                }
+            }
+            BuiltinSource::GCloudAuthorizedUser => {
+                checked.push(format!("`{GCLOUD_CMD}` on the PATH"));
+                let result = match self.lazy {
+                    true => GCloudAuthorizedUser::lazy(),
+                    false => GCloudAuthorizedUser::new().await,
+                };
+                match result {
+                    Ok(provider) => Ok(Arc::new(self.configure(provider))),
+                    Err(err) if is_not_found(&err) => {
+                        Err(AttemptOutcome::Skipped("`gcloud` is not installed"))
+                    }
+                    Err(err) => Err(AttemptOutcome::Failed(err)),
+                }
+            }
+        }
+    }
+
+    fn configure<P: Configure>(&self, mut provider: P) -> P {
+        if let Some(margin) = self.expiry_margin {
+            provider = provider.with_expiry_margin(margin);
+        }
+        if let Some(refresh) = self.background_refresh {
+            provider = provider.with_background_refresh(refresh);
+        }
+        if let Some(clock) = &self.clock {
+            provider = provider.with_clock(clock.clone());
+        }
+        provider
+    }
+
+    fn default_sources() -> Self {
+        Self {
+            steps: BuiltinSource::ALL
+                .iter()
+                .copied()
+                .map(Step::Builtin)
+                .collect(),
+            client: None,
+            expiry_margin: None,
+            background_refresh: None,
+            clock: None,
+            lazy: false,
+        }
+    }
+}
+
+impl Default for ProviderChain {
+    /// Create a chain trying all built-in sources, in the default order
+    fn default() -> Self {
+        Self::default_sources()
+    }
+}
+
+impl fmt::Debug for ProviderChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProviderChain")
+            .field("steps", &self.steps)
+            .field("client", &self.client)
+            .field("expiry_margin", &self.expiry_margin)
+            .field("background_refresh", &self.background_refresh)
+            .field("clock", &self.clock)
+            .field("lazy", &self.lazy)
+            .finish()
+    }
+}
+
+/// The built-in token sources, in their default order
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BuiltinSource {
+    /// Service account credentials from the path in `GOOGLE_APPLICATION_CREDENTIALS`
+    CustomServiceAccount,
+    /// User credentials from the `gcloud` configuration directory
+    ConfigDefaultCredentials,
+    /// The default service account from the instance metadata server
+    MetadataServiceAccount,
+    /// Tokens from the `gcloud` CLI
+    GCloudAuthorizedUser,
+}
+
+impl BuiltinSource {
+    /// The name of the source, which is also the name of its provider type
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::CustomServiceAccount => "CustomServiceAccount",
+            Self::ConfigDefaultCredentials => "ConfigDefaultCredentials",
+            Self::MetadataServiceAccount => "MetadataServiceAccount",
+            Self::GCloudAuthorizedUser => "GCloudAuthorizedUser",
+        }
+    }
+
+    const ALL: [Self; 4] = [
+        Self::CustomServiceAccount,
+        Self::ConfigDefaultCredentials,
+        Self::MetadataServiceAccount,
+        Self::GCloudAuthorizedUser,
+    ];
+}
+
+/// The outcome of [`ProviderChain::resolve()`]
+pub struct ChainReport {
+    attempts: Vec<Attempt>,
+    provider: Option<Arc<dyn TokenProvider>>,
+    error: Option<Error>,
+}
+
+impl ChainReport {
+    /// The sources that were tried, in order
+    pub fn attempts(&self) -> &[Attempt] {
+        &self.attempts
+    }
+
+    /// The provider that was found, if any
+    pub fn provider(&self) -> Option<&Arc<dyn TokenProvider>> {
+        self.provider.as_ref()
+    }
+
+    /// The error which prevented trying any source, if any
+    ///
+    /// This is the case if no [`HttpClient`] was configured and creating one failed.
+    pub fn error(&self) -> Option<&Error> {
+        self.error.as_ref()
+    }
+
+    /// The provider that was found, or an error describing why none was found
+    ///
+    /// If no provider was found, the error is an [`Error::NoAuthMethod`] containing the report.
+    pub fn into_provider(mut self) -> Result<Arc<dyn TokenProvider>, Error> {
+        if let Some(provider) = self.provider.take() {
+            return Ok(provider);
+        } else if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+
+        Err(Error::NoAuthMethod(Box::new(self)))
+    }
+}
+
+impl fmt::Display for ChainReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.provider, &self.error) {
+            (Some(_), _) => f.write_str("found an authentication method")?,
+            (None, Some(err)) => {
+                return write!(f, "failed to look for authentication methods: {err}")
+            }
+            (None, None) => f.write_str("no available authentication method found")?,
+        }
+
+        if self.attempts.is_empty() {
+            return f.write_str(" (no sources configured)");
+        }
+
+        for attempt in &self.attempts {
+            write!(f, "\n  {attempt}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for ChainReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChainReport")
+            .field("attempts", &self.attempts)
+            .field("provider", &self.provider.is_some())
+            .field("error", &self.error)
+            .finish()
+    }
+}
+
+/// A source tried by a [`ProviderChain`]
+#[derive(Debug)]
+pub struct Attempt {
+    source: &'static str,
+    outcome: AttemptOutcome,
+    checked: Vec<String>,
+    duration: Duration,
+}
+
+impl Attempt {
+    /// The name of the source, see [`BuiltinSource::name()`]
+    pub fn source(&self) -> &'static str {
+        self.source
+    }
+
+    /// The outcome of trying the source
+    pub fn outcome(&self) -> &AttemptOutcome {
+        &self.outcome
+    }
+
+    /// The paths and URLs checked for the source
+    pub fn checked(&self) -> &[String] {
+        &self.checked
+    }
+
+    /// How long the probe of the source took, or ran until it was cancelled
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+}
+
+impl fmt::Display for Attempt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({}ms): ", self.source, self.duration.as_millis())?;
+        match &self.outcome {
+            AttemptOutcome::Selected => f.write_str("selected")?,
+            AttemptOutcome::Cancelled => f.write_str("cancelled")?,
+            AttemptOutcome::Skipped(reason) => write!(f, "skipped ({reason})")?,
+            AttemptOutcome::Failed(err) => {
+                // Most variants include their source in the message, but not all of them
+                let mut message = err.to_string();
+                let mut source = std::error::Error::source(err);
+                while let Some(err) = source {
+                    let cause = err.to_string();
+                    if !message.contains(&cause) {
+                        message.push_str(": ");
+                        message.push_str(&cause);
+                    }
+                    source = err.source();
+                }
+                write!(f, "failed: {message}")?;
+            }
+        }
+
+        if !self.checked.is_empty() {
+            write!(f, "\n    checked: {}", self.checked.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// The outcome of trying a source in a [`ProviderChain`]
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum AttemptOutcome {
+    /// The source provided a token provider, which was selected
+    Selected,
+    /// The source is not configured in this environment, for the given reason
+    Skipped(&'static str),
+    /// The source failed with the given error
+    Failed(Error),
+    /// The source was not used because a source before it decided the outcome
+    ///
+    /// Its probe was cancelled if it was still running.
+    Cancelled,
+}
+
+/// Whether the error was caused by a missing file or command
+fn is_not_found(err: &Error) -> bool {
+    match err {
+        Error::Io(_, err) => err.kind() == io::ErrorKind::NotFound,
+        Error::Shared(err) => is_not_found(err),
+        _ => false,
+    }
+}
+
+enum Step {
+    Builtin(BuiltinSource),
+    Custom {
+        name: &'static str,
+        factory: Box<Factory>,
+    },
+}
+
+impl Step {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Builtin(source) => source.name(),
+            Self::Custom { name, .. } => name,
+        }
+    }
+}
+
+impl PartialEq for Step {
+    fn eq(&self, other: &Self) -> bool {
+        matches!((self, other), (Self::Builtin(a), Self::Builtin(b)) if a == b)
+    }
+}
+
+impl fmt::Debug for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Builtin(source) => source.fmt(f),
+            Self::Custom { name, .. } => f.debug_tuple("Custom").field(name).finish(),
+        }
+    }
+}
+
+type Factory = dyn Fn(
+        HttpClient,
+    ) -> Pin<Box<dyn Future<Output = Result<Option<Arc<dyn TokenProvider>>, Error>> + Send>>
+    + Send
+    + Sync;
+
+/// Configuration shared by the built-in providers
+trait Configure {
+    fn with_expiry_margin(self, margin: Duration) -> Self;
+    fn with_background_refresh(self, refresh: BackgroundRefresh) -> Self;
+    fn with_clock(self, clock: Arc<dyn Clock>) -> Self;
+}
+
+macro_rules! impl_configure {
+    ($($provider:ty),*) => {
+        $(impl Configure for $provider {
+            fn with_expiry_margin(self, margin: Duration) -> Self {
+                self.with_expiry_margin(margin)
+            }
+
+            fn with_background_refresh(self, refresh: BackgroundRefresh) -> Self {
+                self.with_background_refresh(refresh)
+            }
+
+            fn with_clock(self, clock: Arc<dyn Clock>) -> Self {
+                self.with_clock(clock)
+            }
+        })*
+    };
+}
+
+impl_configure!(
+    ConfigDefaultCredentials,
+    MetadataServiceAccount,
+    GCloudAuthorizedUser
+);
+#[cfg(any(feature = "ring", feature = "aws-lc-rs"))]
+impl_configure!(CustomServiceAccount);
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use http::Response;
+
+    use super::*;
+    use crate::{InMemoryTransport, RetryPolicy};
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let transport = Arc::new(metadata_transport());
+        let client = HttpClient::builder().build_with_transport(transport.clone());
+
+        let report = ProviderChain::new()
+            .with_client(client)
+            .with_provider("skipped", |_| async { Ok(None) })
+            .with_provider("failed", |_| async { Err(Error::Str("failed")) })
+            .with_source(BuiltinSource::MetadataServiceAccount)
+            .with_provider("cancelled", |_| std::future::pending())
+            .resolve()
+            .await;
+
+        let attempts = report
+            .attempts()
+            .iter()
+            .map(|attempt| (attempt.source(), attempt.outcome()))
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            attempts[..],
+            [
+                ("skipped", AttemptOutcome::Skipped("not available")),
+                ("failed", AttemptOutcome::Failed(_)),
+                ("MetadataServiceAccount", AttemptOutcome::Selected),
+                ("cancelled", AttemptOutcome::Cancelled),
+            ]
+        ));
+        assert_eq!(transport.take_requests().len(), 2);
+
+        let provider = report.into_provider().unwrap();
+        assert_eq!(&*provider.project_id().await.unwrap(), "project");
+        assert_eq!(provider.token(&[]).await.unwrap().as_str(), "abc123");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_precedence() {
+        let client = HttpClient::builder().build_with_transport(Arc::new(metadata_transport()));
+        let provider = |delay| {
+            move |client: HttpClient| async move {
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+                let provider = MetadataServiceAccount::with_client(&client).await?;
+                Ok(Some(Arc::new(provider) as Arc<dyn TokenProvider>))
+            }
+        };
+
+        // The slower source is selected, since it comes first
+        let report = ProviderChain::new()
+            .with_client(client)
+            .with_provider("skipped", |_| async {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                Ok(None)
+            })
+            .with_provider("slow", provider(100))
+            .with_provider("fast", provider(10))
+            .with_provider("hanging", |_| std::future::pending())
+            .resolve()
+            .await;
+
+        let attempts = report
+            .attempts()
+            .iter()
+            .map(|attempt| (attempt.source(), attempt.outcome(), attempt.duration()))
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            attempts[..],
+            [
+                ("skipped", AttemptOutcome::Skipped(_), _),
+                ("slow", AttemptOutcome::Selected, _),
+                ("fast", AttemptOutcome::Cancelled, _),
+                ("hanging", AttemptOutcome::Cancelled, _),
+            ]
+        ));
+        let durations = attempts.iter().map(|a| a.2.as_millis()).collect::<Vec<_>>();
+        assert_eq!(durations, [20, 100, 10, 100]);
+        assert!(report.provider().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_lazy_detection() {
+        // The metadata server is available, but the token endpoint is down
+        let transport = Arc::new(InMemoryTransport::new(|request| {
+            match request.uri().path().ends_with("/project-id") {
+                true => Ok(Response::new(Bytes::from_static(b"project"))),
+                false => Err(Error::Str("token endpoint unavailable")),
+            }
+        }));
+        let client = HttpClient::builder()
+            .with_retry_policy(RetryPolicy::never())
+            .build_with_transport(transport.clone());
+        let chain = || {
+            ProviderChain::new()
+                .with_client(client.clone())
+                .with_source(BuiltinSource::MetadataServiceAccount)
+        };
+
+        let report = chain().resolve().await;
+        assert!(matches!(
+            report.attempts()[0].outcome(),
+            AttemptOutcome::Failed(_)
+        ));
+        transport.take_requests();
+
+        let provider = chain().with_lazy_detection(true).build().await.unwrap();
+        let requests = transport.take_requests();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].uri().path().ends_with("/project-id"));
+
+        assert_eq!(&*provider.project_id().await.unwrap(), "project");
+        assert!(provider.token(&[]).await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_no_provider() {
+        let client = HttpClient::builder()
+            .build_with_transport(Arc::new(InMemoryTransport::new(|_| unreachable!())));
+        let err = ProviderChain::new()
+            .with_client(client)
+            .with_provider("first", |_| async { Ok(None) })
+            .with_provider("second", |_| async {
+                Err(Error::Io(
+                    "failed to read credentials",
+                    io::Error::new(io::ErrorKind::PermissionDenied, "permission denied"),
+                ))
+            })
+            .build()
+            .await
+            .err()
+            .unwrap();
+
+        let Error::NoAuthMethod(report) = &err else {
+            panic!("unexpected error: {err:?}");
+        };
+        assert_eq!(report.attempts().len(), 2);
+        assert_eq!(
+            err.to_string(),
+            "no available authentication method found\n  \
+             first (0ms): skipped (not available)\n  \
+             second (0ms): failed: failed to read credentials: permission denied"
+        );
+    }
+
+    #[test]
+    fn test_sources() {
+        let chain = ProviderChain::default()
+            .without_source(BuiltinSource::GCloudAuthorizedUser)
+            .with_source(BuiltinSource::CustomServiceAccount);
+        assert_eq!(
+            chain.steps,
+            [
+                Step::Builtin(BuiltinSource::ConfigDefaultCredentials),
+                Step::Builtin(BuiltinSource::MetadataServiceAccount),
+                Step::Builtin(BuiltinSource::CustomServiceAccount),
+            ]
+        );
+    }
+
+    /// Answer requests to the metadata server
+    fn metadata_transport() -> InMemoryTransport {
+        InMemoryTransport::new(|request| {
+            let body = match request.uri().path().ends_with("/project-id") {
+                true => Bytes::from_static(b"project"),
+                false => Bytes::from_static(br#"{"access_token":"abc123","expires_in":3600}"#),
+            };
+            Ok(Response::new(body))
+        })
+    }
+}
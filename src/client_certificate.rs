@@ -0,0 +1,245 @@
+use std::borrow::Cow;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use serde::Deserialize;
+use tracing::debug;
+use url::Url;
+
+use crate::gcloud_config::config_dir;
+use crate::tls::parse_pem;
+use crate::Error;
+
+/// A client certificate for mutual TLS, as used for certificate-based access
+///
+/// When a client certificate is configured, it is presented to the server and tokens are
+/// requested from the mTLS variants of Google's token endpoints. By default, the
+/// [`HttpClient`](crate::HttpClient) loads the certificate with
+/// [`ClientCertificate::from_env()`].
+#[derive(Clone)]
+pub struct ClientCertificate {
+    chain: Vec<CertificateDer<'static>>,
+    key: Arc<PrivateKeyDer<'static>>,
+}
+
+impl ClientCertificate {
+    /// Use the certificate chain and private key from the given PEM data
+    pub fn from_pem(chain: &[u8], key: &[u8]) -> Result<Self, Error> {
+        Ok(Self {
+            chain: parse_pem(chain)?,
+            key: Arc::new(
+                PrivateKeyDer::from_pem_slice(key).map_err(|err| {
+                    Error::Other("failed to parse PEM private key", Box::new(err))
+                })?,
+            ),
+        })
+    }
+
+    /// Read the certificate chain and private key from the given PEM files
+    pub fn from_files(chain: impl AsRef<Path>, key: impl AsRef<Path>) -> Result<Self, Error> {
+        let chain =
+            fs::read(chain).map_err(|err| Error::Io("failed to read client certificate", err))?;
+        let key = fs::read(key).map_err(|err| Error::Io("failed to read client key", err))?;
+        Self::from_pem(&chain, &key)
+    }
+
+    /// Load the certificate configured for the environment, if any
+    ///
+    /// If `GOOGLE_API_USE_CLIENT_CERTIFICATE` is `false`, no certificate is used. Otherwise,
+    /// the certificate is loaded from the certificate configuration at the path in
+    /// `GOOGLE_API_CERTIFICATE_CONFIG`, or from `certificate_config.json` in the `gcloud`
+    /// configuration directory. Unless `GOOGLE_API_USE_CLIENT_CERTIFICATE` is `true`, no
+    /// certificate is used if the configuration is missing, invalid or has no workload
+    /// certificate; if it is `true`, these are errors.
+    pub fn from_env() -> Result<Option<Self>, Error> {
+        let required = match env::var(ENV_USE_CLIENT_CERTIFICATE).as_deref() {
+            Ok("false") => return Ok(None),
+            Ok("true") => true,
+            Ok("") | Err(_) => false,
+            Ok(_) => {
+                return Err(Error::Str(
+                    "GOOGLE_API_USE_CLIENT_CERTIFICATE must be `true` or `false`",
+                ))
+            }
+        };
+
+        let path = match env::var_os(ENV_CERTIFICATE_CONFIG).filter(|path| !path.is_empty()) {
+            Some(path) => PathBuf::from(path),
+            None => match config_dir() {
+                Ok(dir) => dir.join(CERTIFICATE_CONFIG_FILE),
+                Err(_) if !required => return Ok(None),
+                Err(err) => return Err(err),
+            },
+        };
+
+        Self::load(&path, required)
+    }
+
+    /// Load the certificate configuration, ignoring errors unless the certificate is `required`
+    fn load(path: &Path, required: bool) -> Result<Option<Self>, Error> {
+        match Self::from_certificate_config(path) {
+            Ok(cert) => Ok(Some(cert)),
+            Err(err) if !required => {
+                debug!(?path, %err, "not using client certificate");
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Load the workload certificate from the given `certificate_config.json`
+    pub fn from_certificate_config(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        debug!(?path, "reading certificate configuration");
+        let config = fs::read(path)
+            .map_err(|err| Error::Io("failed to read certificate configuration", err))?;
+        let config = serde_json::from_slice::<CertificateConfig>(&config)
+            .map_err(|err| Error::Json("failed to parse certificate configuration", err))?;
+        let workload = config.cert_configs.workload.ok_or(Error::Str(
+            "certificate configuration has no workload certificate",
+        ))?;
+
+        // Relative paths are resolved against the directory of the configuration
+        let dir = path.parent().unwrap_or(Path::new(""));
+        Self::from_files(dir.join(workload.cert_path), dir.join(workload.key_path))
+    }
+
+    /// The certificate chain, starting with the client certificate
+    pub fn certificate_chain(&self) -> &[CertificateDer<'static>] {
+        &self.chain
+    }
+
+    /// The private key of the client certificate
+    pub fn private_key(&self) -> &PrivateKeyDer<'static> {
+        &self.key
+    }
+}
+
+impl fmt::Debug for ClientCertificate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientCertificate")
+            .field("chain", &self.chain.len())
+            .field("key", &"****")
+            .finish()
+    }
+}
+
+/// The mTLS variant of a Google API endpoint
+///
+/// Hosts under `googleapis.com` are moved under `mtls.googleapis.com`, and the legacy OAuth
+/// token endpoints are replaced by the current one. Other URLs are returned unchanged.
+pub(crate) fn mtls_endpoint(uri: &str) -> Cow<'_, str> {
+    if LEGACY_TOKEN_URIS.contains(&uri) {
+        return Cow::Borrowed(MTLS_TOKEN_URI);
+    }
+
+    let Ok(mut url) = Url::parse(uri) else {
+        return Cow::Borrowed(uri);
+    };
+    let Some(host) = url.host_str() else {
+        return Cow::Borrowed(uri);
+    };
+
+    match host.strip_suffix(".googleapis.com") {
+        Some(service) if !service.ends_with(".mtls") && url.scheme() == "https" => {
+            let host = format!("{service}.mtls.googleapis.com");
+            match url.set_host(Some(&host)) {
+                Ok(()) => Cow::Owned(url.into()),
+                Err(_) => Cow::Borrowed(uri),
+            }
+        }
+        _ => Cow::Borrowed(uri),
+    }
+}
+
+#[derive(Deserialize)]
+struct CertificateConfig {
+    cert_configs: CertConfigs,
+}
+
+#[derive(Deserialize)]
+struct CertConfigs {
+    workload: Option<WorkloadCertificate>,
+}
+
+#[derive(Deserialize)]
+struct WorkloadCertificate {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+}
+
+const ENV_USE_CLIENT_CERTIFICATE: &str = "GOOGLE_API_USE_CLIENT_CERTIFICATE";
+const ENV_CERTIFICATE_CONFIG: &str = "GOOGLE_API_CERTIFICATE_CONFIG";
+const CERTIFICATE_CONFIG_FILE: &str = "certificate_config.json";
+const LEGACY_TOKEN_URIS: &[&str] = &[
+    "https://accounts.google.com/o/oauth2/token",
+    "https://www.googleapis.com/oauth2/v4/token",
+];
+const MTLS_TOKEN_URI: &str = "https://oauth2.mtls.googleapis.com/token";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mtls_endpoint() {
+        for (uri, expected) in [
+            (
+                "https://oauth2.googleapis.com/token",
+                "https://oauth2.mtls.googleapis.com/token",
+            ),
+            (
+                "https://accounts.google.com/o/oauth2/token",
+                "https://oauth2.mtls.googleapis.com/token",
+            ),
+            (
+                "https://sts.googleapis.com/v1/token",
+                "https://sts.mtls.googleapis.com/v1/token",
+            ),
+            (
+                "https://oauth2.mtls.googleapis.com/token",
+                "https://oauth2.mtls.googleapis.com/token",
+            ),
+            ("https://example.com/token", "https://example.com/token"),
+        ] {
+            assert_eq!(mtls_endpoint(uri), expected);
+        }
+    }
+
+    #[test]
+    fn test_certificate_config() {
+        let dir = env::temp_dir().join(format!("gcp_auth-cert-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let testdata = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata");
+        fs::copy(testdata.join("client.pem"), dir.join("client.pem")).unwrap();
+
+        let config = serde_json::json!({
+            "cert_configs": {
+                "workload": {
+                    "cert_path": "client.pem",
+                    "key_path": testdata.join("client.key"),
+                }
+            }
+        });
+        let path = dir.join(CERTIFICATE_CONFIG_FILE);
+        fs::write(&path, config.to_string()).unwrap();
+
+        let cert = ClientCertificate::from_certificate_config(&path).unwrap();
+        assert_eq!(cert.certificate_chain().len(), 1);
+        assert!(!format!("{cert:?}").contains("BEGIN"));
+
+        fs::write(&path, r#"{"cert_configs":{}}"#).unwrap();
+        assert!(ClientCertificate::from_certificate_config(&path).is_err());
+        assert!(ClientCertificate::load(&path, false).unwrap().is_none());
+        assert!(ClientCertificate::load(&path, true).is_err());
+        assert!(ClientCertificate::load(&dir.join("missing.json"), false)
+            .unwrap()
+            .is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,31 +1,37 @@
-#[cfg(target_family = "unix")]
-use std::env;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use async_trait::async_trait;
 use bytes::Bytes;
-use http_body_util::Full;
 use hyper::header::CONTENT_TYPE;
 use hyper::{Method, Request};
 use serde::Serialize;
-use tokio::sync::RwLock;
 use tracing::{debug, instrument, Level};
 
+use crate::cache::{BackgroundRefresh, TokenCache, TokenSource};
+use crate::gcloud_config::config_dir;
 use crate::types::{AuthorizedUserRefreshToken, HttpClient, Token};
-use crate::{Error, TokenProvider};
+use crate::{quota_project_id, Clock, Error, GCloudConfig, TokenProvider};
 
 /// A token provider that uses the default user credentials
 ///
 /// Reads credentials from `.config/gcloud/application_default_credentials.json` on Linux and MacOS
-/// or from `%APPDATA%/gcloud/application_default_credentials.json` on Windows.
+/// or from `%APPDATA%/gcloud/application_default_credentials.json` on Windows. If the
+/// `CLOUDSDK_CONFIG` environment variable is set, the credentials are read from that directory
+/// instead. Alternatively, credentials can be read from a custom path or JSON string.
 /// See [GCloud Application Default Credentials](https://cloud.google.com/docs/authentication/application-default-credentials#personal)
 /// for details.
+///
+/// The project ID is taken from the `quota_project_id` in the credentials, falling back to the
+/// project set in the active `gcloud` configuration (see [`GCloudConfig`]). The quota project
+/// returned by [`TokenProvider::quota_project_id()`] is only taken from the credentials.
 #[derive(Debug)]
 pub struct ConfigDefaultCredentials {
-    client: HttpClient,
-    token: RwLock<Arc<Token>>,
-    credentials: AuthorizedUserRefreshToken,
+    source: Arc<RefreshTokenSource>,
+    token: TokenCache<()>,
+    gcloud_project_id: Option<Arc<str>>,
 }
 
 impl ConfigDefaultCredentials {
@@ -35,35 +41,149 @@ impl ConfigDefaultCredentials {
         Self::with_client(&client).await
     }
 
-    pub(crate) async fn with_client(client: &HttpClient) -> Result<Self, Error> {
+    /// Read user credentials from the given JSON file
+    pub async fn from_file<T: AsRef<Path>>(path: T) -> Result<Self, Error> {
+        let credentials = AuthorizedUserRefreshToken::from_file(path)?;
+        Self::with_credentials(credentials, &HttpClient::new()?).await
+    }
+
+    /// Read user credentials from the given JSON string
+    pub async fn from_json(s: &str) -> Result<Self, Error> {
+        let credentials = AuthorizedUserRefreshToken::from_str(s)?;
+        Self::with_credentials(credentials, &HttpClient::new()?).await
+    }
+
+    /// Like [`ConfigDefaultCredentials::new()`], requesting tokens with the given [`HttpClient`]
+    pub async fn with_client(client: &HttpClient) -> Result<Self, Error> {
+        let provider = Self::lazy(client)?;
+        provider.token.get(()).await?;
+        Ok(provider)
+    }
+
+    /// Use the given user credentials, requesting tokens with the given [`HttpClient`]
+    pub async fn with_credentials(
+        credentials: AuthorizedUserRefreshToken,
+        client: &HttpClient,
+    ) -> Result<Self, Error> {
+        let provider = Self::lazy_with_credentials(credentials, client);
+        provider.token.get(()).await?;
+        Ok(provider)
+    }
+
+    /// Like [`ConfigDefaultCredentials::with_client()`], but without requesting a token
+    ///
+    /// The credentials are read and validated, but the first token is only requested when it
+    /// is needed.
+    pub fn lazy(client: &HttpClient) -> Result<Self, Error> {
         debug!("try to load credentials from configuration");
-        let mut config_path = config_dir()?;
-        config_path.push(USER_CREDENTIALS_PATH);
+        let config_path = credentials_path()?;
         debug!(config = config_path.to_str(), "reading configuration file");
 
         let credentials = AuthorizedUserRefreshToken::from_file(&config_path)?;
+        Ok(Self::lazy_with_credentials(credentials, client))
+    }
+
+    /// Like [`ConfigDefaultCredentials::with_credentials()`], but without requesting a token
+    pub fn lazy_with_credentials(
+        credentials: AuthorizedUserRefreshToken,
+        client: &HttpClient,
+    ) -> Self {
         debug!(project = ?credentials.quota_project_id, client = credentials.client_id, "found user credentials");
 
-        Ok(Self {
+        let gcloud_project_id = match credentials.quota_project_id {
+            Some(_) => None,
+            None => GCloudConfig::load()
+                .ok()
+                .and_then(|config| config.project()),
+        };
+
+        let source = Arc::new(RefreshTokenSource {
             client: client.clone(),
-            token: RwLock::new(Self::fetch_token(&credentials, client).await?),
             credentials,
-        })
+        });
+
+        Self {
+            token: TokenCache::new(source.clone()),
+            source,
+            gcloud_project_id,
+        }
     }
 
-    #[instrument(level = Level::DEBUG, skip(cred, client))]
-    async fn fetch_token(
-        cred: &AuthorizedUserRefreshToken,
-        client: &HttpClient,
+    /// Refresh the cached token once it expires within the given margin (default 20 seconds)
+    pub fn with_expiry_margin(mut self, margin: Duration) -> Self {
+        self.token.set_expiry_margin(margin);
+        self
+    }
+
+    /// Refresh the cached token in the background before it expires
+    pub fn with_background_refresh(mut self, refresh: BackgroundRefresh) -> Self {
+        self.token.set_background_refresh(refresh);
+        self
+    }
+
+    /// Use the given clock to determine when the cached token expires
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.token.set_clock(clock);
+        self
+    }
+}
+
+#[async_trait]
+impl TokenProvider for ConfigDefaultCredentials {
+    async fn token(&self, _scopes: &[&str]) -> Result<Arc<Token>, Error> {
+        self.token.get(()).await
+    }
+
+    async fn token_valid_for(
+        &self,
+        _scopes: &[&str],
+        min_lifetime: Duration,
     ) -> Result<Arc<Token>, Error> {
-        client
+        self.token.get_valid_for((), min_lifetime).await
+    }
+
+    async fn invalidate(&self, _scopes: &[&str]) {
+        self.token.invalidate(&());
+    }
+
+    async fn invalidate_all(&self) {
+        self.token.invalidate_all();
+    }
+
+    async fn project_id(&self) -> Result<Arc<str>, Error> {
+        self.source
+            .credentials
+            .quota_project_id
+            .clone()
+            .or_else(|| self.gcloud_project_id.clone())
+            .ok_or(Error::Str("no project ID in user credentials"))
+    }
+
+    async fn quota_project_id(&self) -> Option<Arc<str>> {
+        quota_project_id(self.source.credentials.quota_project_id.as_ref())
+    }
+}
+
+#[derive(Debug)]
+struct RefreshTokenSource {
+    client: HttpClient,
+    credentials: AuthorizedUserRefreshToken,
+}
+
+#[async_trait]
+impl TokenSource<()> for RefreshTokenSource {
+    #[instrument(level = Level::DEBUG, skip(self))]
+    async fn fetch(&self, _: &(), clock: &dyn Clock) -> Result<Arc<Token>, Error> {
+        let cred = &self.credentials;
+        let uri = self.client.endpoint(DEFAULT_TOKEN_GCP_URI);
+        self.client
             .token(
                 &|| {
                     Request::builder()
                         .method(Method::POST)
-                        .uri(DEFAULT_TOKEN_GCP_URI)
+                        .uri(&*uri)
                         .header(CONTENT_TYPE, "application/json")
-                        .body(Full::from(Bytes::from(
+                        .body(Bytes::from(
                             serde_json::to_vec(&RefreshRequest {
                                 client_id: &cred.client_id,
                                 client_secret: &cred.client_secret,
@@ -71,35 +191,19 @@ impl ConfigDefaultCredentials {
                                 refresh_token: &cred.refresh_token,
                             })
                             .unwrap(),
-                        )))
+                        ))
                         .unwrap()
                 },
                 "ConfigDefaultCredentials",
+                clock,
             )
             .await
     }
 }
 
-#[async_trait]
-impl TokenProvider for ConfigDefaultCredentials {
-    async fn token(&self, _scopes: &[&str]) -> Result<Arc<Token>, Error> {
-        let token = self.token.read().await.clone();
-        if !token.has_expired() {
-            return Ok(token);
-        }
-
-        let mut locked = self.token.write().await;
-        let token = Self::fetch_token(&self.credentials, &self.client).await?;
-        *locked = token.clone();
-        Ok(token)
-    }
-
-    async fn project_id(&self) -> Result<Arc<str>, Error> {
-        self.credentials
-            .quota_project_id
-            .clone()
-            .ok_or(Error::Str("no project ID in user credentials"))
-    }
+/// The path of the default user credentials file
+pub(crate) fn credentials_path() -> Result<PathBuf, Error> {
+    Ok(config_dir()?.join(USER_CREDENTIALS_FILE))
 }
 
 #[derive(Serialize, Debug)]
@@ -110,29 +214,5 @@ struct RefreshRequest<'a> {
     refresh_token: &'a str,
 }
 
-#[cfg(target_family = "unix")]
-fn config_dir() -> Result<PathBuf, Error> {
-    let mut home = env::home_dir().ok_or(Error::Str("home directory not found"))?;
-    home.push(CONFIG_DIR);
-    Ok(home)
-}
-
-#[cfg(target_family = "windows")]
-fn config_dir() -> Result<PathBuf, Error> {
-    let app_data = std::env::var(ENV_APPDATA)
-        .map_err(|_| Error::Str("APPDATA environment variable not found"))?;
-    let config_path = PathBuf::from(app_data);
-    match config_path.exists() {
-        true => Ok(config_path),
-        false => Err(Error::Str("APPDATA directory not found")),
-    }
-}
-
 const DEFAULT_TOKEN_GCP_URI: &str = "https://accounts.google.com/o/oauth2/token";
-const USER_CREDENTIALS_PATH: &str = "gcloud/application_default_credentials.json";
-
-#[cfg(target_family = "unix")]
-const CONFIG_DIR: &str = ".config";
-
-#[cfg(target_family = "windows")]
-const ENV_APPDATA: &str = "APPDATA";
+const USER_CREDENTIALS_FILE: &str = "application_default_credentials.json";
@@ -1,22 +1,21 @@
-use std::collections::HashMap;
 use std::path::Path;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use async_trait::async_trait;
 use base64::{engine::general_purpose::URL_SAFE, Engine};
 use bytes::Bytes;
-use chrono::Utc;
-use http_body_util::Full;
+use chrono::{DateTime, Utc};
 use hyper::header::CONTENT_TYPE;
 use hyper::Request;
 use serde::Serialize;
-use tokio::sync::RwLock;
 use tracing::{debug, instrument, Level};
 use url::form_urlencoded;
 
+use crate::cache::{BackgroundRefresh, CacheStats, TokenCache, TokenSource};
 use crate::types::{HttpClient, ServiceAccountKey, Signer, Token};
-use crate::{Error, TokenProvider};
+use crate::{quota_project_id, Clock, Error, TokenProvider};
 
 /// A custom service account containing credentials
 ///
@@ -26,12 +25,8 @@ use crate::{Error, TokenProvider};
 /// [`AuthenticationManager`]: crate::AuthenticationManager
 #[derive(Debug)]
 pub struct CustomServiceAccount {
-    client: HttpClient,
-    credentials: ServiceAccountKey,
-    signer: Signer,
-    tokens: RwLock<HashMap<Vec<String>, Arc<Token>>>,
-    subject: Option<String>,
-    audience: Option<String>,
+    source: Arc<ServiceAccountSource>,
+    tokens: TokenCache<Vec<String>>,
 }
 
 impl CustomServiceAccount {
@@ -44,6 +39,13 @@ impl CustomServiceAccount {
         }
     }
 
+    pub(crate) fn from_env_with_client(client: &HttpClient) -> Result<Option<Self>, Error> {
+        match ServiceAccountKey::from_env()? {
+            Some(credentials) => Self::new(credentials, client.clone()).map(Some),
+            None => Ok(None),
+        }
+    }
+
     /// Read service account credentials from the given JSON file
     pub fn from_file<T: AsRef<Path>>(path: T) -> Result<Self, Error> {
         Self::new(ServiceAccountKey::from_file(path)?, HttpClient::new()?)
@@ -54,37 +56,154 @@ impl CustomServiceAccount {
         Self::new(ServiceAccountKey::from_str(s)?, HttpClient::new()?)
     }
 
+    /// Request tokens with the given [`HttpClient`]
+    pub fn with_client(mut self, client: HttpClient) -> Self {
+        Arc::make_mut(&mut self.source).client = client;
+        self.tokens.set_source(self.source.clone());
+        self
+    }
+
     /// Set the `subject` to impersonate a user
     pub fn with_subject(mut self, subject: String) -> Self {
-        self.subject = Some(subject);
+        Arc::make_mut(&mut self.source).subject = Some(subject);
+        self.tokens.set_source(self.source.clone());
         self
     }
 
     /// Set the `Audience` to impersonate a user
     pub fn with_audience(mut self, audience: String) -> Self {
-        self.audience = Some(audience);
+        Arc::make_mut(&mut self.source).audience = Some(audience);
+        self.tokens.set_source(self.source.clone());
+        self
+    }
+
+    /// Refresh cached tokens once they expire within the given margin (default 20 seconds)
+    pub fn with_expiry_margin(mut self, margin: Duration) -> Self {
+        self.tokens.set_expiry_margin(margin);
+        self
+    }
+
+    /// Refresh cached tokens in the background before they expire
+    pub fn with_background_refresh(mut self, refresh: BackgroundRefresh) -> Self {
+        self.tokens.set_background_refresh(refresh);
+        self
+    }
+
+    /// Use the given clock to determine when cached tokens expire and to sign JWTs
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.tokens.set_clock(clock);
         self
     }
 
+    /// Limit the number of cached tokens (default 128)
+    ///
+    /// Tokens are cached per set of scopes. Once the limit is reached, expired tokens are
+    /// evicted first, followed by the least recently used tokens.
+    pub fn with_cache_capacity(mut self, capacity: usize) -> Self {
+        self.tokens.set_capacity(capacity);
+        self
+    }
+
+    /// Statistics about the use of the token cache
+    pub fn cache_stats(&self) -> CacheStats {
+        self.tokens.stats()
+    }
+
     fn new(credentials: ServiceAccountKey, client: HttpClient) -> Result<Self, Error> {
         debug!(project = ?credentials.project_id, email = credentials.client_email, "found credentials");
-        Ok(Self {
+        let source = Arc::new(ServiceAccountSource {
             client,
-            signer: Signer::new(&credentials.private_key)?,
-            credentials,
-            tokens: RwLock::new(HashMap::new()),
+            signer: Arc::new(Signer::new(&credentials.private_key)?),
+            credentials: Arc::new(credentials),
             subject: None,
             audience: None,
-        })
+        });
+
+        let mut tokens = TokenCache::new(source.clone());
+        tokens.set_capacity(DEFAULT_CACHE_CAPACITY);
+        Ok(Self { tokens, source })
+    }
+
+    /// The RSA PKCS1 SHA256 [`Signer`] used to sign JWT tokens
+    pub fn signer(&self) -> &Signer {
+        &self.source.signer
+    }
+
+    /// The project ID as found in the credentials
+    pub fn project_id(&self) -> Option<&str> {
+        self.source.credentials.project_id.as_deref()
+    }
+
+    /// The private key as found in the credentials
+    pub fn private_key_pem(&self) -> &str {
+        &self.source.credentials.private_key
+    }
+}
+
+#[async_trait]
+impl TokenProvider for CustomServiceAccount {
+    async fn token(&self, scopes: &[&str]) -> Result<Arc<Token>, Error> {
+        self.tokens.get(scope_key(scopes)).await
+    }
+
+    async fn token_valid_for(
+        &self,
+        scopes: &[&str],
+        min_lifetime: Duration,
+    ) -> Result<Arc<Token>, Error> {
+        self.tokens
+            .get_valid_for(scope_key(scopes), min_lifetime)
+            .await
+    }
+
+    async fn invalidate(&self, scopes: &[&str]) {
+        self.tokens.invalidate(&scope_key(scopes));
+    }
+
+    async fn invalidate_all(&self) {
+        self.tokens.invalidate_all();
+    }
+
+    async fn project_id(&self) -> Result<Arc<str>, Error> {
+        match &self.source.credentials.project_id {
+            Some(pid) => Ok(pid.clone()),
+            None => Err(Error::Str("no project ID in application credentials")),
+        }
+    }
+
+    async fn quota_project_id(&self) -> Option<Arc<str>> {
+        quota_project_id(self.source.credentials.quota_project_id.as_ref())
     }
+}
+
+/// Normalize scopes into a cache key, so the order and duplicates don't matter
+fn scope_key(scopes: &[&str]) -> Vec<String> {
+    let mut key = scopes.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+    key.sort_unstable();
+    key.dedup();
+    key
+}
+
+#[derive(Clone, Debug)]
+struct ServiceAccountSource {
+    client: HttpClient,
+    credentials: Arc<ServiceAccountKey>,
+    signer: Arc<Signer>,
+    subject: Option<String>,
+    audience: Option<String>,
+}
 
+#[async_trait]
+impl TokenSource<Vec<String>> for ServiceAccountSource {
     #[instrument(level = Level::DEBUG, skip(self))]
-    async fn fetch_token(&self, scopes: &[&str]) -> Result<Arc<Token>, Error> {
+    async fn fetch(&self, scopes: &Vec<String>, clock: &dyn Clock) -> Result<Arc<Token>, Error> {
+        let scopes: Vec<_> = scopes.iter().map(String::as_str).collect();
         let jwt = Claims::new(
             &self.credentials,
-            scopes,
+            &scopes,
             self.subject.as_deref(),
             self.audience.as_deref(),
+            clock.now(),
         )
         .to_jwt(&self.signer)?;
         let body = Bytes::from(
@@ -94,66 +213,23 @@ impl CustomServiceAccount {
                 .into_bytes(),
         );
 
+        let token_uri = self.client.endpoint(&self.credentials.token_uri);
         let token = self
             .client
             .token(
                 &|| {
-                    Request::post(&self.credentials.token_uri)
+                    Request::post(&*token_uri)
                         .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
-                        .body(Full::from(body.clone()))
+                        .body(body.clone())
                         .unwrap()
                 },
                 "CustomServiceAccount",
+                clock,
             )
             .await?;
 
         Ok(token)
     }
-
-    /// The RSA PKCS1 SHA256 [`Signer`] used to sign JWT tokens
-    pub fn signer(&self) -> &Signer {
-        &self.signer
-    }
-
-    /// The project ID as found in the credentials
-    pub fn project_id(&self) -> Option<&str> {
-        self.credentials.project_id.as_deref()
-    }
-
-    /// The private key as found in the credentials
-    pub fn private_key_pem(&self) -> &str {
-        &self.credentials.private_key
-    }
-}
-
-#[async_trait]
-impl TokenProvider for CustomServiceAccount {
-    async fn token(&self, scopes: &[&str]) -> Result<Arc<Token>, Error> {
-        let key: Vec<_> = scopes.iter().map(|x| x.to_string()).collect();
-        let token = self.tokens.read().await.get(&key).cloned();
-        if let Some(token) = token {
-            if !token.has_expired() {
-                return Ok(token.clone());
-            }
-
-            let mut locked = self.tokens.write().await;
-            let token = self.fetch_token(scopes).await?;
-            locked.insert(key, token.clone());
-            return Ok(token);
-        }
-
-        let mut locked = self.tokens.write().await;
-        let token = self.fetch_token(scopes).await?;
-        locked.insert(key, token.clone());
-        return Ok(token);
-    }
-
-    async fn project_id(&self) -> Result<Arc<str>, Error> {
-        match &self.credentials.project_id {
-            Some(pid) => Ok(pid.clone()),
-            None => Err(Error::Str("no project ID in application credentials")),
-        }
-    }
 }
 
 /// Permissions requested for a JWT.
@@ -174,6 +250,7 @@ impl<'a> Claims<'a> {
         scopes: &[&str],
         sub: Option<&'a str>,
         aud: Option<&'a str>,
+        now: DateTime<Utc>,
     ) -> Self {
         let mut scope = String::with_capacity(16);
         for (i, s) in scopes.iter().enumerate() {
@@ -184,7 +261,7 @@ impl<'a> Claims<'a> {
             scope.push_str(s);
         }
 
-        let iat = Utc::now().timestamp();
+        let iat = now.timestamp();
         Claims {
             iss: &key.client_email,
             aud: aud.unwrap_or(&key.token_uri),
@@ -210,3 +287,34 @@ impl<'a> Claims<'a> {
 
 pub(crate) const GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:jwt-bearer";
 const GOOGLE_RS256_HEAD: &str = r#"{"alg":"RS256","typ":"JWT"}"#;
+const DEFAULT_CACHE_CAPACITY: usize = 128;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_claims() {
+        let key = ServiceAccountKey::from_str(
+            r#"{
+              "client_email": "test@example.iam.gserviceaccount.com",
+              "private_key": "",
+              "token_uri": "https://oauth2.googleapis.com/token"
+            }"#,
+        )
+        .unwrap();
+
+        let now = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let claims = Claims::new(&key, &["a", "b"], None, None, now);
+        assert_eq!(claims.iat, 1_700_000_000);
+        assert_eq!(claims.exp, 1_700_000_000 + 3595);
+        assert_eq!(claims.scope, "a b");
+        assert_eq!(claims.aud, "https://oauth2.googleapis.com/token");
+    }
+
+    #[test]
+    fn test_scope_key() {
+        assert_eq!(scope_key(&["b", "a", "b"]), scope_key(&["a", "b"]),);
+        assert_eq!(scope_key(&["b", "a"]), vec!["a", "b"]);
+    }
+}
@@ -0,0 +1,497 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use fs4::fs_std::FileExt;
+use tokio::io::AsyncWriteExt;
+use tokio::time::sleep;
+use tracing::{debug, warn};
+
+use crate::types::EXPIRY_MARGIN;
+use crate::{Clock, Error, SystemClock, Token, TokenProvider};
+
+/// A token provider that caches tokens from another provider on disk
+///
+/// Tokens are stored in a JSON file in the given directory, keyed by an identity for the
+/// credentials of the wrapped provider and the (sorted, deduplicated) scopes. This allows
+/// short-lived processes using the same credentials to reuse unexpired tokens instead of each
+/// requesting a new token.
+///
+/// The identity must uniquely identify the credentials used by the wrapped provider, for
+/// example the service account email or the path to the credentials file. Tokens for
+/// different identities can share the same directory.
+///
+/// The cache file is replaced atomically and only readable by the current user. Processes
+/// lock a lock file while fetching a token, so that only one of them requests a new token
+/// while the others wait for it. Failures to read or write the cache are logged and the
+/// token is requested from the wrapped provider instead.
+pub struct FileCache {
+    provider: Arc<dyn TokenProvider>,
+    identity: Arc<str>,
+    path: PathBuf,
+    tokens: Mutex<HashMap<String, Arc<Token>>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl FileCache {
+    /// Cache tokens in the default cache directory
+    ///
+    /// This is `$XDG_CACHE_HOME/gcp_auth` (defaulting to `~/.cache/gcp_auth`) on Linux and
+    /// MacOS, or `%LOCALAPPDATA%/gcp_auth` on Windows.
+    pub fn new(provider: Arc<dyn TokenProvider>, identity: &str) -> Result<Self, Error> {
+        let mut dir = user_cache_dir()?;
+        dir.push(CACHE_DIR);
+        Ok(Self::with_dir(provider, identity, dir))
+    }
+
+    /// Cache tokens in the given directory
+    pub fn with_dir(
+        provider: Arc<dyn TokenProvider>,
+        identity: &str,
+        dir: impl AsRef<Path>,
+    ) -> Self {
+        Self {
+            provider,
+            identity: Arc::from(identity),
+            path: dir.as_ref().join(CACHE_FILE),
+            tokens: Mutex::new(HashMap::new()),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Use the given clock to determine when cached tokens expire
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    async fn get(
+        &self,
+        scopes: &[&str],
+        min_lifetime: Option<Duration>,
+    ) -> Result<Arc<Token>, Error> {
+        let key = self.key(scopes);
+        let margin = min_lifetime.unwrap_or(EXPIRY_MARGIN);
+        if let Some(token) = self.cached(&key, margin) {
+            return Ok(token);
+        }
+
+        if let Some(token) = self.load(&key, margin).await {
+            return Ok(token);
+        }
+
+        let lock = match FileLock::acquire(self.path.with_extension(LOCK_EXTENSION)).await {
+            Ok(lock) => Some(lock),
+            Err(err) => {
+                warn!(path = ?self.path, %err, "failed to lock token cache");
+                None
+            }
+        };
+
+        // Another process may have fetched a token while we were waiting for the lock
+        if lock.is_some() {
+            if let Some(token) = self.load(&key, margin).await {
+                return Ok(token);
+            }
+        }
+
+        let token = match min_lifetime {
+            Some(min_lifetime) => self.provider.token_valid_for(scopes, min_lifetime).await?,
+            None => self.provider.token(scopes).await?,
+        };
+
+        self.tokens
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(key.clone(), token.clone());
+        if lock.is_some() {
+            let result = self
+                .update(|tokens| {
+                    tokens.insert(key, token.clone());
+                })
+                .await;
+            if let Err(err) = result {
+                warn!(path = ?self.path, %err, "failed to write token cache");
+            }
+        }
+
+        Ok(token)
+    }
+
+    /// Drop matching tokens from memory and from the cache file
+    async fn remove(&self, matches: impl Fn(&str) -> bool) {
+        self.tokens
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|key, _| !matches(key));
+        let result = match FileLock::acquire(self.path.with_extension(LOCK_EXTENSION)).await {
+            Ok(_lock) => {
+                self.update(|tokens| tokens.retain(|key, _| !matches(key)))
+                    .await
+            }
+            Err(err) => Err(err),
+        };
+
+        if let Err(err) = result {
+            warn!(path = ?self.path, %err, "failed to remove tokens from cache");
+        }
+    }
+
+    /// Get a token from the in-memory cache
+    fn cached(&self, key: &str, margin: Duration) -> Option<Arc<Token>> {
+        let tokens = self.tokens.lock().unwrap_or_else(PoisonError::into_inner);
+        tokens
+            .get(key)
+            .filter(|token| !token.expires_within(margin, self.clock.now()))
+            .cloned()
+    }
+
+    /// Get a token from the cache file, and keep it in memory for subsequent calls
+    async fn load(&self, key: &str, margin: Duration) -> Option<Arc<Token>> {
+        let mut tokens = match read_tokens(&self.path).await {
+            Ok(tokens) => tokens,
+            Err(err) => {
+                warn!(path = ?self.path, %err, "failed to read token cache");
+                return None;
+            }
+        };
+
+        let token = tokens.remove(key)?;
+        if token.expires_within(margin, self.clock.now()) {
+            return None;
+        }
+
+        debug!(path = ?self.path, "using token from cache file");
+        self.tokens
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(key.to_owned(), token.clone());
+        Some(token)
+    }
+
+    /// Modify the tokens in the cache file, dropping expired tokens
+    ///
+    /// Must only be called while holding the lock file.
+    async fn update(
+        &self,
+        f: impl FnOnce(&mut BTreeMap<String, Arc<Token>>),
+    ) -> Result<(), io::Error> {
+        let mut tokens = read_tokens(&self.path).await.unwrap_or_default();
+        let now = self.clock.now();
+        tokens.retain(|_, token| !token.expires_within(Duration::ZERO, now));
+        f(&mut tokens);
+
+        if let Some(dir) = self.path.parent() {
+            create_private_dir(dir).await?;
+        }
+
+        let tmp = self
+            .path
+            .with_extension(format!("{}.tmp", std::process::id()));
+        let result = async {
+            let mut file = open_private(&tmp, OpenMode::Truncate).await?;
+            file.write_all(&serde_json::to_vec(&tokens)?).await?;
+            file.sync_all().await?;
+            drop(file);
+            tokio::fs::rename(&tmp, &self.path).await
+        };
+
+        match result.await {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                let _ = tokio::fs::remove_file(&tmp).await;
+                Err(err)
+            }
+        }
+    }
+
+    fn key(&self, scopes: &[&str]) -> String {
+        let mut scopes = scopes.to_vec();
+        scopes.sort_unstable();
+        scopes.dedup();
+        format!("{} {}", self.identity, scopes.join(" "))
+    }
+}
+
+#[async_trait]
+impl TokenProvider for FileCache {
+    async fn token(&self, scopes: &[&str]) -> Result<Arc<Token>, Error> {
+        self.get(scopes, None).await
+    }
+
+    async fn token_valid_for(
+        &self,
+        scopes: &[&str],
+        min_lifetime: Duration,
+    ) -> Result<Arc<Token>, Error> {
+        self.get(scopes, Some(min_lifetime.max(EXPIRY_MARGIN)))
+            .await
+    }
+
+    async fn invalidate(&self, scopes: &[&str]) {
+        let key = self.key(scopes);
+        self.remove(|k| k == key).await;
+        self.provider.invalidate(scopes).await;
+    }
+
+    async fn invalidate_all(&self) {
+        let prefix = format!("{} ", self.identity);
+        self.remove(|key| key.starts_with(&prefix)).await;
+        self.provider.invalidate_all().await;
+    }
+
+    async fn project_id(&self) -> Result<Arc<str>, Error> {
+        self.provider.project_id().await
+    }
+
+    async fn quota_project_id(&self) -> Option<Arc<str>> {
+        self.provider.quota_project_id().await
+    }
+}
+
+impl fmt::Debug for FileCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileCache")
+            .field("identity", &self.identity)
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+/// An exclusive advisory lock on a lock file, released when dropped
+///
+/// The operating system releases the lock if the process exits, so locks can't go stale. The
+/// lock file itself is left in place, since removing it could race with other processes.
+#[derive(Debug)]
+struct FileLock {
+    _file: fs::File,
+}
+
+impl FileLock {
+    async fn acquire(path: PathBuf) -> Result<Self, io::Error> {
+        if let Some(dir) = path.parent() {
+            create_private_dir(dir).await?;
+        }
+
+        let file = open_private(&path, OpenMode::Lock).await?.into_std().await;
+        let start = Instant::now();
+        while !FileExt::try_lock_exclusive(&file)? {
+            if start.elapsed() > LOCK_TIMEOUT {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "timed out waiting for lock file",
+                ));
+            }
+
+            sleep(LOCK_RETRY).await;
+        }
+
+        Ok(Self { _file: file })
+    }
+}
+
+async fn read_tokens(path: &Path) -> Result<BTreeMap<String, Arc<Token>>, io::Error> {
+    match tokio::fs::read(path).await {
+        Ok(contents) => Ok(serde_json::from_slice(&contents)?),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Open a file for writing which is only accessible by the current user
+async fn open_private(path: &Path, mode: OpenMode) -> Result<tokio::fs::File, io::Error> {
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create(true);
+    match mode {
+        OpenMode::Truncate => options.truncate(true),
+        OpenMode::Lock => options.truncate(false),
+    };
+
+    #[cfg(target_family = "unix")]
+    options.mode(0o600);
+    options.open(path).await
+}
+
+#[derive(Clone, Copy)]
+enum OpenMode {
+    /// Replace the contents of the file
+    Truncate,
+    /// Keep the contents, which are unused for lock files
+    Lock,
+}
+
+async fn create_private_dir(dir: &Path) -> Result<(), io::Error> {
+    let mut builder = tokio::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(target_family = "unix")]
+    builder.mode(0o700);
+    builder.create(dir).await
+}
+
+#[cfg(target_family = "unix")]
+fn user_cache_dir() -> Result<PathBuf, Error> {
+    if let Some(dir) = std::env::var_os(ENV_XDG_CACHE_HOME).filter(|dir| !dir.is_empty()) {
+        return Ok(PathBuf::from(dir));
+    }
+
+    let mut home = std::env::home_dir().ok_or(Error::Str("home directory not found"))?;
+    home.push(".cache");
+    Ok(home)
+}
+
+#[cfg(target_family = "windows")]
+fn user_cache_dir() -> Result<PathBuf, Error> {
+    std::env::var_os(ENV_LOCALAPPDATA)
+        .map(PathBuf::from)
+        .ok_or(Error::Str("LOCALAPPDATA environment variable not found"))
+}
+
+const CACHE_DIR: &str = "gcp_auth";
+const CACHE_FILE: &str = "tokens.json";
+const LOCK_EXTENSION: &str = "json.lock";
+const LOCK_RETRY: Duration = Duration::from_millis(50);
+const LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[cfg(target_family = "unix")]
+const ENV_XDG_CACHE_HOME: &str = "XDG_CACHE_HOME";
+
+#[cfg(target_family = "windows")]
+const ENV_LOCALAPPDATA: &str = "LOCALAPPDATA";
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct CountingProvider {
+        fetches: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl TokenProvider for CountingProvider {
+        async fn token(&self, scopes: &[&str]) -> Result<Arc<Token>, Error> {
+            let n = self.fetches.fetch_add(1, Ordering::SeqCst) + 1;
+            Ok(Arc::new(Token::from_string(
+                format!("{}-{n}", scopes.join(",")),
+                Duration::from_secs(3600),
+            )))
+        }
+
+        async fn project_id(&self) -> Result<Arc<str>, Error> {
+            Err(Error::Str("no project ID"))
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("gcp_auth-{name}-{}", std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn test_shared_between_caches() {
+        let dir = temp_dir("file-cache");
+        let provider = Arc::new(CountingProvider::default());
+
+        let first = FileCache::with_dir(provider.clone(), "a@example.com", &dir);
+        let token = first.token(&["x", "y"]).await.unwrap();
+        assert_eq!(token.as_str(), "x,y-1");
+
+        // A second cache (as used by another process) reuses the token from disk
+        let second = FileCache::with_dir(provider.clone(), "a@example.com", &dir);
+        let token = second.token(&["y", "x", "y"]).await.unwrap();
+        assert_eq!(token.as_str(), "x,y-1");
+        assert_eq!(provider.fetches.load(Ordering::SeqCst), 1);
+
+        // Tokens are not shared between identities
+        let other = FileCache::with_dir(provider.clone(), "b@example.com", &dir);
+        assert_eq!(other.token(&["x", "y"]).await.unwrap().as_str(), "x,y-2");
+
+        // The token does not live long enough, so a new one is requested
+        let result = second
+            .token_valid_for(&["x", "y"], Duration::from_secs(7200))
+            .await;
+        assert!(result.is_err());
+
+        #[cfg(target_family = "unix")]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(dir.join(CACHE_FILE))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // The lock file is kept, but no longer locked
+        let lock = tokio::time::timeout(
+            Duration::from_millis(200),
+            FileLock::acquire(dir.join("tokens.json.lock")),
+        );
+        assert!(lock.await.unwrap().is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_expired_tokens() {
+        let dir = temp_dir("file-cache-expired");
+        let provider = Arc::new(CountingProvider::default());
+        let cache = FileCache::with_dir(provider.clone(), "a@example.com", &dir);
+
+        let expired = Arc::new(Token::from_string(
+            "expired".to_owned(),
+            Duration::from_secs(10),
+        ));
+        {
+            let _lock = FileLock::acquire(dir.join("tokens.json.lock"))
+                .await
+                .unwrap();
+            cache
+                .update(|tokens| {
+                    tokens.insert(cache.key(&["x"]), expired);
+                })
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(cache.token(&["x"]).await.unwrap().as_str(), "x-1");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_invalidate() {
+        let dir = temp_dir("file-cache-invalidate");
+        let provider = Arc::new(CountingProvider::default());
+        let first = FileCache::with_dir(provider.clone(), "a@example.com", &dir);
+        let second = FileCache::with_dir(provider.clone(), "a@example.com", &dir);
+        assert_eq!(first.token(&["x"]).await.unwrap().as_str(), "x-1");
+        assert_eq!(first.token(&["y"]).await.unwrap().as_str(), "y-2");
+
+        first.invalidate(&["x"]).await;
+        assert_eq!(second.token(&["x"]).await.unwrap().as_str(), "x-3");
+        assert_eq!(second.token(&["y"]).await.unwrap().as_str(), "y-2");
+
+        second.invalidate_all().await;
+        assert_eq!(second.token(&["y"]).await.unwrap().as_str(), "y-4");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_lock() {
+        let dir = temp_dir("file-cache-lock");
+        let path = dir.join("tokens.json.lock");
+        let lock = FileLock::acquire(path.clone()).await.unwrap();
+        let timeout =
+            tokio::time::timeout(Duration::from_millis(200), FileLock::acquire(path.clone()));
+        assert!(timeout.await.is_err());
+
+        drop(lock);
+        let _lock = FileLock::acquire(path).await.unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
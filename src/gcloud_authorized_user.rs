@@ -1,54 +1,130 @@
-use std::process::Command;
+use std::env;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
 use async_trait::async_trait;
-use tokio::sync::RwLock;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use tokio::process::Command;
 use tracing::{debug, instrument};
 
+use crate::cache::{BackgroundRefresh, TokenCache, TokenSource};
 use crate::types::Token;
-use crate::{Error, TokenProvider};
+use crate::{quota_project_id, Clock, Error, GCloudConfig, TokenProvider};
 
 /// A token provider that queries the `gcloud` CLI for access tokens
+///
+/// Tokens are obtained from `gcloud config config-helper`, which reports the actual expiry
+/// of the (possibly cached) token handed out by `gcloud`.
 #[derive(Debug)]
 pub struct GCloudAuthorizedUser {
     project_id: Option<Arc<str>>,
-    token: RwLock<Arc<Token>>,
+    quota_project_id: Option<Arc<str>>,
+    account: Option<Arc<str>>,
+    token: TokenCache<()>,
+    source: Arc<GCloudSource>,
 }
 
 impl GCloudAuthorizedUser {
     /// Check if `gcloud` is installed and logged in
     pub async fn new() -> Result<Self, Error> {
-        debug!("try to print access token via `gcloud`");
-        let token = RwLock::new(Self::fetch_token()?);
-        let project_id = run(&["config", "get-value", "project"]).ok();
+        debug!("try to get access token via `gcloud config config-helper`");
+        let helper = ConfigHelper::run(false).await?;
+        let source = Arc::new(GCloudSource::default());
+        let (project_id, account) = (helper.project_id(), helper.account());
+        debug!(project = ?project_id, account = ?account, "found `gcloud` configuration");
         Ok(Self {
-            project_id: project_id.map(Arc::from),
-            token,
+            project_id,
+            quota_project_id: helper.quota_project_id(),
+            account,
+            token: TokenCache::with_token(source.clone(), (), helper.into_token(Utc::now())?),
+            source,
         })
     }
 
-    #[instrument(level = tracing::Level::DEBUG)]
-    fn fetch_token() -> Result<Arc<Token>, Error> {
-        Ok(Arc::new(Token::from_string(
-            run(&["auth", "print-access-token", "--quiet"])?,
-            DEFAULT_TOKEN_DURATION,
-        )))
+    /// Check if `gcloud` is installed and logged in, without getting a token
+    ///
+    /// This checks that `gcloud` is on the `PATH` and reads the active configuration (see
+    /// [`GCloudConfig`]) without running `gcloud`. It is only run to request the first token,
+    /// once it is needed.
+    pub fn lazy() -> Result<Self, Error> {
+        if !on_path(GCLOUD_CMD) {
+            return Err(Error::Io(
+                "failed to find `gcloud`",
+                io::Error::new(io::ErrorKind::NotFound, "`gcloud` is not on the PATH"),
+            ));
+        }
+
+        let config = GCloudConfig::load().map_err(|err| match err {
+            Error::Io(_, err) if err.kind() == io::ErrorKind::NotFound => {
+                Error::Str("no active `gcloud` configuration")
+            }
+            err => err,
+        })?;
+        let (project_id, account) = (config.project(), config.account());
+        if account.is_none() {
+            return Err(Error::Str("`gcloud` is not logged in"));
+        }
+
+        debug!(project = ?project_id, account = ?account, "found `gcloud` configuration");
+        let source = Arc::new(GCloudSource::default());
+        Ok(Self {
+            project_id,
+            quota_project_id: config.get("billing", "quota_project"),
+            account,
+            token: TokenCache::new(source.clone()),
+            source,
+        })
+    }
+
+    /// The account `gcloud` is logged in with, if known
+    pub fn account(&self) -> Option<&str> {
+        self.account.as_deref()
+    }
+
+    /// Refresh the cached token once it expires within the given margin (default 20 seconds)
+    pub fn with_expiry_margin(mut self, margin: Duration) -> Self {
+        self.token.set_expiry_margin(margin);
+        self
+    }
+
+    /// Refresh the cached token in the background before it expires
+    pub fn with_background_refresh(mut self, refresh: BackgroundRefresh) -> Self {
+        self.token.set_background_refresh(refresh);
+        self
+    }
+
+    /// Use the given clock to determine when the cached token expires
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.token.set_clock(clock);
+        self
     }
 }
 
 #[async_trait]
 impl TokenProvider for GCloudAuthorizedUser {
     async fn token(&self, _scopes: &[&str]) -> Result<Arc<Token>, Error> {
-        let token = self.token.read().await.clone();
-        if !token.has_expired() {
-            return Ok(token);
-        }
+        self.token.get(()).await
+    }
 
-        let mut locked = self.token.write().await;
-        let token = Self::fetch_token()?;
-        *locked = token.clone();
-        Ok(token)
+    async fn token_valid_for(
+        &self,
+        _scopes: &[&str],
+        min_lifetime: Duration,
+    ) -> Result<Arc<Token>, Error> {
+        self.token.get_valid_for((), min_lifetime).await
+    }
+
+    async fn invalidate(&self, _scopes: &[&str]) {
+        self.source.force_refresh();
+        self.token.invalidate(&());
+    }
+
+    async fn invalidate_all(&self) {
+        self.source.force_refresh();
+        self.token.invalidate_all();
     }
 
     async fn project_id(&self) -> Result<Arc<str>, Error> {
@@ -56,13 +132,134 @@ impl TokenProvider for GCloudAuthorizedUser {
             .clone()
             .ok_or(Error::Str("failed to get project ID from `gcloud`"))
     }
+
+    async fn quota_project_id(&self) -> Option<Arc<str>> {
+        quota_project_id(self.quota_project_id.as_ref())
+    }
+}
+
+#[derive(Debug, Default)]
+struct GCloudSource {
+    /// Whether `gcloud` should refresh its token instead of handing out the one it cached
+    force_refresh: AtomicBool,
+}
+
+impl GCloudSource {
+    /// Make the next fetch bypass the token cached by `gcloud`
+    fn force_refresh(&self) {
+        self.force_refresh.store(true, Ordering::Relaxed);
+    }
+}
+
+#[async_trait]
+impl TokenSource<()> for GCloudSource {
+    #[instrument(level = tracing::Level::DEBUG, skip(self))]
+    async fn fetch(&self, _: &(), clock: &dyn Clock) -> Result<Arc<Token>, Error> {
+        let force_refresh = self.force_refresh.swap(false, Ordering::Relaxed);
+        let result = ConfigHelper::run(force_refresh).await;
+        if force_refresh && result.is_err() {
+            self.force_refresh();
+        }
+        result?.into_token(clock.now())
+    }
 }
 
-fn run(cmd: &[&str]) -> Result<String, Error> {
+/// Output of `gcloud config config-helper --format=json`
+#[derive(Deserialize)]
+struct ConfigHelper {
+    configuration: HelperConfiguration,
+    credential: HelperCredential,
+}
+
+impl ConfigHelper {
+    async fn run(force_refresh: bool) -> Result<Self, Error> {
+        Self::from_slice(run(&Self::args(force_refresh)).await?.as_bytes())
+    }
+
+    fn args(force_refresh: bool) -> Vec<&'static str> {
+        let mut args = vec!["config", "config-helper", "--format=json", "--quiet"];
+        if force_refresh {
+            args.push("--force-auth-refresh");
+        }
+        args
+    }
+
+    fn from_slice(s: &[u8]) -> Result<Self, Error> {
+        serde_json::from_slice(s).map_err(|err| {
+            Error::Json(
+                "failed to deserialize `gcloud config config-helper` output",
+                err,
+            )
+        })
+    }
+
+    fn into_token(self, now: DateTime<Utc>) -> Result<Arc<Token>, Error> {
+        let access_token = self
+            .credential
+            .access_token
+            .ok_or(Error::Str("no access token in `gcloud` credential"))?;
+
+        let token = match self.credential.token_expiry {
+            Some(expires_at) => Token::new(access_token, expires_at),
+            None => Token::new(access_token, now + DEFAULT_TOKEN_DURATION),
+        };
+
+        Ok(Arc::new(
+            token
+                .with_token_type("Bearer")
+                .with_provider("GCloudAuthorizedUser"),
+        ))
+    }
+
+    fn project_id(&self) -> Option<Arc<str>> {
+        self.configuration.properties.core.project.clone()
+    }
+
+    fn account(&self) -> Option<Arc<str>> {
+        self.configuration.properties.core.account.clone()
+    }
+
+    fn quota_project_id(&self) -> Option<Arc<str>> {
+        self.configuration.properties.billing.quota_project.clone()
+    }
+}
+
+#[derive(Deserialize)]
+struct HelperConfiguration {
+    properties: HelperProperties,
+}
+
+#[derive(Deserialize)]
+struct HelperProperties {
+    #[serde(default)]
+    core: HelperCoreProperties,
+    #[serde(default)]
+    billing: HelperBillingProperties,
+}
+
+#[derive(Default, Deserialize)]
+struct HelperBillingProperties {
+    quota_project: Option<Arc<str>>,
+}
+
+#[derive(Default, Deserialize)]
+struct HelperCoreProperties {
+    account: Option<Arc<str>>,
+    project: Option<Arc<str>>,
+}
+
+#[derive(Deserialize)]
+struct HelperCredential {
+    access_token: Option<String>,
+    token_expiry: Option<DateTime<Utc>>,
+}
+
+async fn run(cmd: &[&str]) -> Result<String, Error> {
     let mut command = Command::new(GCLOUD_CMD);
-    command.args(cmd);
+    // Don't leave `gcloud` running if the probe is cancelled
+    command.args(cmd).kill_on_drop(true);
 
-    let mut stdout = match command.output() {
+    let mut stdout = match command.output().await {
         Ok(output) if output.status.success() => output.stdout,
         Ok(_) => return Err(Error::Str("running `gcloud` command failed")),
         Err(err) => return Err(Error::Io("failed to run `gcloud`", err)),
@@ -75,15 +272,23 @@ fn run(cmd: &[&str]) -> Result<String, Error> {
     String::from_utf8(stdout).map_err(|_| Error::Str("output from `gcloud` is not UTF-8"))
 }
 
+/// Whether the given command is a file in one of the directories on the `PATH`
+fn on_path(cmd: &str) -> bool {
+    env::var_os("PATH")
+        .is_some_and(|path| env::split_paths(&path).any(|dir| dir.join(cmd).is_file()))
+}
+
 #[cfg(target_family = "unix")]
-const GCLOUD_CMD: &str = "gcloud";
+pub(crate) const GCLOUD_CMD: &str = "gcloud";
 
 #[cfg(target_family = "windows")]
-const GCLOUD_CMD: &str = "gcloud.cmd";
+pub(crate) const GCLOUD_CMD: &str = "gcloud.cmd";
 
 /// The default number of seconds that it takes for a Google Cloud auth token to expire.
 /// This appears to be the default from practical testing, but we have not found evidence
 /// that this will always be the default duration.
+///
+/// Only used if `gcloud config config-helper` does not report the token expiry.
 pub(crate) const DEFAULT_TOKEN_DURATION: Duration = Duration::from_secs(3600);
 
 #[cfg(test)]
@@ -98,16 +303,25 @@ mod tests {
         let gcloud = GCloudAuthorizedUser::new().await.unwrap();
         println!("{:?}", gcloud.project_id);
         if let Ok(t) = gcloud.token(&[""]).await {
-            let expires = Utc::now() + DEFAULT_TOKEN_DURATION;
             println!("{:?}", t);
             assert!(!t.has_expired());
-            assert!(t.expires_at() < expires + Duration::from_secs(1));
-            assert!(t.expires_at() > expires - Duration::from_secs(1));
+            assert!(t.expires_at() < Utc::now() + DEFAULT_TOKEN_DURATION);
         } else {
             panic!("GCloud Authorized User failed to get a token");
         }
     }
 
+    #[test]
+    fn test_force_refresh_args() {
+        let source = GCloudSource::default();
+        assert!(!source.force_refresh.load(Ordering::Relaxed));
+        assert!(!ConfigHelper::args(false).contains(&"--force-auth-refresh"));
+
+        source.force_refresh();
+        assert!(source.force_refresh.swap(false, Ordering::Relaxed));
+        assert!(ConfigHelper::args(true).contains(&"--force-auth-refresh"));
+    }
+
     /// `gcloud_authorized_user` is the only user type to get a token that isn't deserialized from
     /// JSON, and that doesn't include an expiry time. As such, the default token expiry time
     /// functionality is tested here.
@@ -123,6 +337,66 @@ mod tests {
         assert!(token.expires_at() > expires - Duration::from_secs(1));
     }
 
+    #[test]
+    #[cfg(target_family = "unix")]
+    fn test_on_path() {
+        assert!(on_path("sh"));
+        assert!(!on_path("gcp_auth-no-such-command"));
+    }
+
+    #[test]
+    fn test_config_helper() {
+        let s = br#"{
+          "configuration": {
+            "active_configuration": "default",
+            "properties": {
+              "billing": {
+                "quota_project": "billing-project"
+              },
+              "core": {
+                "account": "user@example.com",
+                "disable_usage_reporting": "True",
+                "project": "my-project"
+              }
+            }
+          },
+          "credential": {
+            "access_token": "abc123",
+            "token_expiry": "2024-01-01T12:34:56Z"
+          },
+          "sentinels": {
+            "config_sentinel": "/home/user/.config/gcloud/config_sentinel"
+          }
+        }"#;
+
+        let helper = ConfigHelper::from_slice(s).unwrap();
+        assert_eq!(helper.project_id().as_deref(), Some("my-project"));
+        assert_eq!(helper.account().as_deref(), Some("user@example.com"));
+        assert_eq!(
+            helper.quota_project_id().as_deref(),
+            Some("billing-project")
+        );
+
+        let token = helper.into_token(Utc::now()).unwrap();
+        assert_eq!(token.as_str(), "abc123");
+        assert_eq!(token.expires_at().to_rfc3339(), "2024-01-01T12:34:56+00:00");
+    }
+
+    #[test]
+    fn test_config_helper_no_project() {
+        let s = br#"{
+          "configuration": {"active_configuration": "default", "properties": {}},
+          "credential": {"access_token": "abc123"}
+        }"#;
+
+        let helper = ConfigHelper::from_slice(s).unwrap();
+        assert_eq!(helper.project_id(), None);
+
+        let now = Utc::now();
+        let token = helper.into_token(now).unwrap();
+        assert_eq!(token.expires_at(), now + DEFAULT_TOKEN_DURATION);
+    }
+
     #[test]
     fn test_deserialize_no_time() {
         let s = r#"{"access_token":"abc123"}"#;
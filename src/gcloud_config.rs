@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use tracing::debug;
+
+use crate::Error;
+
+/// Properties from the active `gcloud` configuration
+///
+/// Reads the INI file for the active configuration from the `gcloud` configuration directory
+/// without running `gcloud` itself. The directory defaults to `~/.config/gcloud` (or
+/// `%APPDATA%/gcloud` on Windows) and can be overridden with the `CLOUDSDK_CONFIG` environment
+/// variable. The active configuration is read from the `active_config` file in that directory,
+/// unless the `CLOUDSDK_ACTIVE_CONFIG_NAME` environment variable is set.
+#[derive(Clone, Debug)]
+pub struct GCloudConfig {
+    name: Arc<str>,
+    properties: HashMap<(String, String), Arc<str>>,
+}
+
+impl GCloudConfig {
+    /// Load the active configuration from the default `gcloud` configuration directory
+    pub fn load() -> Result<Self, Error> {
+        Self::from_dir(config_dir()?)
+    }
+
+    /// Load the active configuration from the given `gcloud` configuration directory
+    pub fn from_dir(dir: impl AsRef<Path>) -> Result<Self, Error> {
+        let dir = dir.as_ref();
+        let name = match env::var(ENV_ACTIVE_CONFIG_NAME) {
+            Ok(name) if !name.is_empty() => name,
+            _ => match fs::read_to_string(dir.join(ACTIVE_CONFIG_FILE)) {
+                Ok(name) => name.trim().to_owned(),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => DEFAULT_CONFIG_NAME.to_owned(),
+                Err(err) => {
+                    return Err(Error::Io("failed to read active gcloud configuration", err))
+                }
+            },
+        };
+
+        let path = dir.join(CONFIGURATIONS_DIR).join(format!("config_{name}"));
+        debug!(config = ?path, "reading gcloud configuration");
+        let contents = fs::read_to_string(&path)
+            .map_err(|err| Error::Io("failed to read gcloud configuration file", err))?;
+
+        Ok(Self {
+            name: Arc::from(name),
+            properties: parse_ini(&contents),
+        })
+    }
+
+    /// The name of the active configuration
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The project set in the `core/project` property
+    pub fn project(&self) -> Option<Arc<str>> {
+        self.get("core", "project")
+    }
+
+    /// The account set in the `core/account` property
+    pub fn account(&self) -> Option<Arc<str>> {
+        self.get("core", "account")
+    }
+
+    /// The region set in the `compute/region` property
+    pub fn region(&self) -> Option<Arc<str>> {
+        self.get("compute", "region")
+    }
+
+    /// Get the value of an arbitrary property in the given section
+    pub fn get(&self, section: &str, property: &str) -> Option<Arc<str>> {
+        self.properties
+            .get(&(section.to_owned(), property.to_owned()))
+            .cloned()
+    }
+}
+
+/// Parse the subset of INI syntax used by `gcloud` configuration files
+fn parse_ini(contents: &str) -> HashMap<(String, String), Arc<str>> {
+    let mut properties = HashMap::new();
+    let mut section = String::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+
+        if let Some(name) = line.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            section = name.trim().to_owned();
+            continue;
+        }
+
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+
+        let value = value.trim();
+        if value.is_empty() {
+            continue;
+        }
+
+        properties.insert((section.clone(), key.trim().to_owned()), Arc::from(value));
+    }
+
+    properties
+}
+
+/// The `gcloud` configuration directory
+///
+/// Honors the `CLOUDSDK_CONFIG` environment variable.
+pub(crate) fn config_dir() -> Result<PathBuf, Error> {
+    if let Some(dir) = env::var_os(ENV_CLOUDSDK_CONFIG).filter(|dir| !dir.is_empty()) {
+        return Ok(PathBuf::from(dir));
+    }
+
+    let mut dir = user_config_dir()?;
+    dir.push(GCLOUD_DIR);
+    Ok(dir)
+}
+
+#[cfg(target_family = "unix")]
+fn user_config_dir() -> Result<PathBuf, Error> {
+    let mut home = env::home_dir().ok_or(Error::Str("home directory not found"))?;
+    home.push(CONFIG_DIR);
+    Ok(home)
+}
+
+#[cfg(target_family = "windows")]
+fn user_config_dir() -> Result<PathBuf, Error> {
+    let app_data =
+        env::var(ENV_APPDATA).map_err(|_| Error::Str("APPDATA environment variable not found"))?;
+    let config_path = PathBuf::from(app_data);
+    match config_path.exists() {
+        true => Ok(config_path),
+        false => Err(Error::Str("APPDATA directory not found")),
+    }
+}
+
+const ENV_CLOUDSDK_CONFIG: &str = "CLOUDSDK_CONFIG";
+const ENV_ACTIVE_CONFIG_NAME: &str = "CLOUDSDK_ACTIVE_CONFIG_NAME";
+const ACTIVE_CONFIG_FILE: &str = "active_config";
+const CONFIGURATIONS_DIR: &str = "configurations";
+const DEFAULT_CONFIG_NAME: &str = "default";
+const GCLOUD_DIR: &str = "gcloud";
+
+#[cfg(target_family = "unix")]
+const CONFIG_DIR: &str = ".config";
+
+#[cfg(target_family = "windows")]
+const ENV_APPDATA: &str = "APPDATA";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ini() {
+        let properties = parse_ini(
+            "[core]\n\
+             account = user@example.com\n\
+             project = my-project\n\
+             # comment\n\
+             disable_usage_reporting =\n\
+             \n\
+             [compute]\n\
+             region = europe-west1\n",
+        );
+
+        let get = |section: &str, key: &str| {
+            properties
+                .get(&(section.to_owned(), key.to_owned()))
+                .map(|s| &**s)
+        };
+        assert_eq!(get("core", "account"), Some("user@example.com"));
+        assert_eq!(get("core", "project"), Some("my-project"));
+        assert_eq!(get("core", "disable_usage_reporting"), None);
+        assert_eq!(get("compute", "region"), Some("europe-west1"));
+        assert_eq!(get("compute", "project"), None);
+    }
+
+    #[test]
+    fn test_from_dir() {
+        let dir = env::temp_dir().join(format!("gcp_auth-gcloud-config-{}", std::process::id()));
+        fs::create_dir_all(dir.join(CONFIGURATIONS_DIR)).unwrap();
+        fs::write(dir.join(ACTIVE_CONFIG_FILE), "work\n").unwrap();
+        fs::write(
+            dir.join(CONFIGURATIONS_DIR).join("config_work"),
+            "[core]\nproject = work-project\n[compute]\nregion = us-east1\n",
+        )
+        .unwrap();
+
+        let config = GCloudConfig::from_dir(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(config.name(), "work");
+        assert_eq!(config.project().as_deref(), Some("work-project"));
+        assert_eq!(config.region().as_deref(), Some("us-east1"));
+        assert_eq!(config.account(), None);
+    }
+}
@@ -10,9 +10,10 @@
 //! 1. Reading custom service account credentials from the path pointed to by the
 //!    `GOOGLE_APPLICATION_CREDENTIALS` environment variable. Alternatively, custom service
 //!    account credentials can be read from a JSON file or string.
-//! 2. Look for credentials in `.config/gcloud/application_default_credentials.json`;
-//!    if found, use these credentials to request refresh tokens. This file can be created
-//!    by invoking `gcloud auth application-default login`.
+//! 2. Look for credentials in `.config/gcloud/application_default_credentials.json`
+//!    (or the directory set in `CLOUDSDK_CONFIG`); if found, use these credentials to
+//!    request refresh tokens. This file can be created by invoking
+//!    `gcloud auth application-default login`.
 //! 3. Use the default service account by retrieving a token from the metadata server.
 //! 4. Retrieving a token from the `gcloud` CLI tool, if it is available on the `PATH`.
 //!
@@ -20,6 +21,14 @@
 //!
 //! A [`TokenProvider`] handles caching tokens for their lifetime; it will not make a request if
 //! an appropriate token is already cached. Therefore, the caller should not cache tokens.
+//! Providers can also be configured to refresh tokens in the background before they expire,
+//! using [`BackgroundRefresh`]. To reuse tokens across short-lived processes, any provider can be
+//! wrapped in a [`FileCache`], which keeps tokens in a file on disk.
+//!
+//! Providers send requests with an [`HttpClient`], which can be shared between providers and
+//! configured through [`HttpClientBuilder`]. To send requests through an existing HTTP client
+//! or to answer them in tests, implement [`Transport`] (or use [`InMemoryTransport`]) and pass
+//! the resulting client to [`provider_with_client()`] or the providers' constructors.
 //!
 //! ## Simple usage
 //!
@@ -36,6 +45,9 @@
 //! # }
 //! ```
 //!
+//! With the `blocking` feature, the [`blocking`] module provides a synchronous API for code
+//! that doesn't run in an async runtime.
+//!
 //! ## Supplying service account credentials
 //!
 //! When running outside of GCP (for example, on a development machine), it can be useful to supply
@@ -62,6 +74,21 @@
 //! # }
 //! ```
 //!
+//! ## Attaching tokens to requests
+//!
+//! [`TokenProvider::headers()`] returns the `authorization` header along with the
+//! `x-goog-user-project` header if a quota project is configured for the credentials (or set
+//! in the `GOOGLE_CLOUD_QUOTA_PROJECT` environment variable).
+//!
+//! ```rust,no_run
+//! # async fn get_headers() -> Result<(), gcp_auth::Error> {
+//! let provider = gcp_auth::provider().await?;
+//! let scopes = &["https://www.googleapis.com/auth/cloud-platform"];
+//! let headers = provider.headers(scopes).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
 //! ## Getting tokens in multi-thread or async environments
 //!
 //! Using a `OnceCell` makes it easy to reuse the [`AuthenticationManager`] across different
@@ -87,17 +114,33 @@
 
 #![warn(unreachable_pub)]
 
+use std::env;
 use std::sync::Arc;
+use std::time::Duration;
 
 use async_trait::async_trait;
+use chrono::Utc;
+use http::header::{HeaderMap, HeaderValue, AUTHORIZATION};
 use thiserror::Error;
 use tracing::{debug, instrument, Level};
 
+#[cfg(feature = "blocking")]
+pub mod blocking;
+
+mod chain;
+pub use chain::{Attempt, AttemptOutcome, BuiltinSource, ChainReport, ProviderChain};
+
 #[cfg(any(feature = "ring", feature = "aws-lc-rs"))]
 mod custom_service_account;
 #[cfg(any(feature = "ring", feature = "aws-lc-rs"))]
 pub use custom_service_account::CustomServiceAccount;
 
+mod client_certificate;
+pub use client_certificate::ClientCertificate;
+
+mod clock;
+pub use clock::{Clock, ManualClock, SystemClock};
+
 mod config_default_credentials;
 pub use config_default_credentials::ConfigDefaultCredentials;
 
@@ -107,11 +150,32 @@ pub use metadata_service_account::MetadataServiceAccount;
 mod gcloud_authorized_user;
 pub use gcloud_authorized_user::GCloudAuthorizedUser;
 
+mod file_cache;
+pub use file_cache::FileCache;
+
+mod gcloud_config;
+pub use gcloud_config::GCloudConfig;
+
+mod cache;
+pub use cache::{BackgroundRefresh, CacheStats};
+
+mod proxy;
+pub use proxy::Proxy;
+
+mod retry;
+pub use retry::RetryPolicy;
+
+mod tls;
+
+mod transport;
+pub use transport::{InMemoryTransport, Transport};
+
 mod types;
-use types::HttpClient;
 #[cfg(any(feature = "ring", feature = "aws-lc-rs"))]
 pub use types::Signer;
-pub use types::Token;
+pub use types::{
+    AuthorizedUserRefreshToken, HttpClient, HttpClientBuilder, OAuthError, ResponseError, Token,
+};
 
 /// Finds a service account provider to get authentication tokens from
 ///
@@ -120,50 +184,47 @@ pub use types::Token;
 /// 1. Check if the `GOOGLE_APPLICATION_CREDENTIALS` environment variable if set;
 ///    if so, use a custom service account as the token source (requires one of
 ///    the `ring` or `aws-lc-rs` features).
-/// 2. Look for credentials in `.config/gcloud/application_default_credentials.json`;
-///    if found, use these credentials to request refresh tokens.
+/// 2. Look for credentials in `.config/gcloud/application_default_credentials.json`
+///    (or the directory set in `CLOUDSDK_CONFIG`); if found, use these credentials to
+///    request refresh tokens.
 /// 3. Send a HTTP request to the internal metadata server to retrieve a token;
 ///    if it succeeds, use the default service account as the token source.
 /// 4. Check if the `gcloud` tool is available on the `PATH`; if so, use the
-///    `gcloud auth print-access-token` command as the token source.
+///    `gcloud config config-helper` command as the token source.
+///
+/// The sources are probed concurrently, but the first source in this order that succeeds is
+/// used; probes of the sources after it are cancelled. To change the order, skip sources or
+/// add custom sources, use a [`ProviderChain`].
+///
+/// The selected provider has already obtained a token. To select a provider without requesting
+/// a token, so that a temporary outage doesn't prevent startup, use [`lazy_provider()`].
 #[instrument(level = Level::DEBUG)]
 pub async fn provider() -> Result<Arc<dyn TokenProvider>, Error> {
-    debug!("initializing gcp_auth");
-    #[cfg(any(feature = "ring", feature = "aws-lc-rs"))]
-    if let Some(provider) = CustomServiceAccount::from_env()? {
-        return Ok(Arc::new(provider));
-    }
-
-    let client = HttpClient::new()?;
-    let default_user_error = match ConfigDefaultCredentials::with_client(&client).await {
-        Ok(provider) => {
-            debug!("using ConfigDefaultCredentials");
-            return Ok(Arc::new(provider));
-        }
-        Err(e) => e,
-    };
+    provider_with_client(&HttpClient::new()?).await
+}
 
-    let default_service_error = match MetadataServiceAccount::with_client(&client).await {
-        Ok(provider) => {
-            debug!("using MetadataServiceAccount");
-            return Ok(Arc::new(provider));
-        }
-        Err(e) => e,
-    };
+/// Like [`provider()`], but without requesting a token
+///
+/// Sources are selected if they are configured, and the first token is only requested when it
+/// is needed. See [`ProviderChain::with_lazy_detection()`].
+#[instrument(level = Level::DEBUG)]
+pub async fn lazy_provider() -> Result<Arc<dyn TokenProvider>, Error> {
+    debug!("initializing gcp_auth");
+    ProviderChain::default()
+        .with_client(HttpClient::new()?)
+        .with_lazy_detection(true)
+        .build()
+        .await
+}
 
-    let gcloud_error = match GCloudAuthorizedUser::new().await {
-        Ok(provider) => {
-            debug!("using GCloudAuthorizedUser");
-            return Ok(Arc::new(provider));
-        }
-        Err(e) => e,
-    };
-
-    Err(Error::NoAuthMethod(
-        Box::new(gcloud_error),
-        Box::new(default_service_error),
-        Box::new(default_user_error),
-    ))
+/// Like [`provider()`], sending all requests with the given [`HttpClient`]
+#[instrument(level = Level::DEBUG)]
+pub async fn provider_with_client(client: &HttpClient) -> Result<Arc<dyn TokenProvider>, Error> {
+    debug!("initializing gcp_auth");
+    ProviderChain::default()
+        .with_client(client.clone())
+        .build()
+        .await
 }
 
 /// A trait for an authentication context that can provide tokens
@@ -172,27 +233,106 @@ pub trait TokenProvider: Send + Sync {
     /// Get a valid token for the given scopes
     ///
     /// Tokens are cached until they expire, so this method will only fetch a fresh token once
-    /// the current token (for the given scopes) has expired.
+    /// the current token (for the given scopes) has expired. Concurrent calls for the same
+    /// scopes share a single token request. If refreshing an expiring token fails, the cached
+    /// token is returned for as long as it is still valid.
     async fn token(&self, scopes: &[&str]) -> Result<Arc<Token>, Error>;
 
+    /// Get a token for the given scopes which is valid for at least `min_lifetime`
+    ///
+    /// Like [`TokenProvider::token()`], but fetches a fresh token if the cached token expires
+    /// within `min_lifetime`. This is useful before starting long-running operations. Note that
+    /// some token sources (like the metadata server) may hand out tokens with less remaining
+    /// lifetime; in that case, the freshly fetched token is returned regardless.
+    ///
+    /// The default implementation returns an error if the token returned by
+    /// [`TokenProvider::token()`] does not have the requested lifetime.
+    async fn token_valid_for(
+        &self,
+        scopes: &[&str],
+        min_lifetime: Duration,
+    ) -> Result<Arc<Token>, Error> {
+        let token = self.token(scopes).await?;
+        match token.expires_at() - min_lifetime > Utc::now() {
+            true => Ok(token),
+            false => Err(Error::Str(
+                "token does not have the requested remaining lifetime",
+            )),
+        }
+    }
+
+    /// Drop the cached token for the given scopes
+    ///
+    /// The next call to [`TokenProvider::token()`] for these scopes fetches a new token. This
+    /// is useful when an API rejects a token with `401 Unauthorized`, for example because it
+    /// was revoked. Providers which use a single token for all scopes drop that token.
+    ///
+    /// The default implementation does nothing.
+    async fn invalidate(&self, _scopes: &[&str]) {}
+
+    /// Drop all cached tokens, so the next call to [`TokenProvider::token()`] fetches a new token
+    ///
+    /// The default implementation does nothing.
+    async fn invalidate_all(&self) {}
+
     /// Get the project ID for the authentication context
     async fn project_id(&self) -> Result<Arc<str>, Error>;
+
+    /// Get the quota project ID for the authentication context, if any
+    ///
+    /// The quota project is billed for API usage and should be sent in the
+    /// `x-goog-user-project` header (see [`TokenProvider::headers()`]). It may differ from
+    /// the project returned by [`TokenProvider::project_id()`]. If the
+    /// `GOOGLE_CLOUD_QUOTA_PROJECT` environment variable is set, it takes precedence over
+    /// the quota project configured for the credentials.
+    async fn quota_project_id(&self) -> Option<Arc<str>> {
+        quota_project_id(None)
+    }
+
+    /// Get the headers to authenticate a request with a token for the given scopes
+    ///
+    /// Contains the `authorization` header with the bearer token and, if there is a
+    /// quota project, the `x-goog-user-project` header.
+    async fn headers(&self, scopes: &[&str]) -> Result<HeaderMap, Error> {
+        let token = self.token(scopes).await?;
+        let mut headers = HeaderMap::with_capacity(2);
+        let mut authorization = HeaderValue::try_from(format!("Bearer {}", token.as_str()))
+            .map_err(|_| Error::Str("token is not a valid header value"))?;
+        authorization.set_sensitive(true);
+        headers.insert(AUTHORIZATION, authorization);
+
+        if let Some(quota_project_id) = self.quota_project_id().await {
+            let value = HeaderValue::try_from(&*quota_project_id)
+                .map_err(|_| Error::Str("quota project ID is not a valid header value"))?;
+            headers.insert(QUOTA_PROJECT_HEADER, value);
+        }
+
+        Ok(headers)
+    }
 }
 
+/// Resolve the quota project, giving precedence to `GOOGLE_CLOUD_QUOTA_PROJECT`
+pub(crate) fn quota_project_id(configured: Option<&Arc<str>>) -> Option<Arc<str>> {
+    match env::var(ENV_QUOTA_PROJECT) {
+        Ok(project) if !project.is_empty() => Some(Arc::from(project)),
+        _ => configured.cloned(),
+    }
+}
+
+/// Name of the header used to specify the quota project for a request
+pub const QUOTA_PROJECT_HEADER: &str = "x-goog-user-project";
+
+const ENV_QUOTA_PROJECT: &str = "GOOGLE_CLOUD_QUOTA_PROJECT";
+
 /// Enumerates all possible errors returned by this library.
 #[derive(Error, Debug)]
 pub enum Error {
     /// No available authentication method was discovered
     ///
-    /// Application can authenticate against GCP using:
-    ///
-    /// - Default service account - available inside GCP platform using GCP Instance Metadata server
-    /// - GCloud authorized user - retrieved using `gcloud auth` command
-    ///
-    /// All authentication methods have been tested and none succeeded.
-    /// Service account file can be downloaded from GCP in json format.
-    #[error("no available authentication method found")]
-    NoAuthMethod(Box<Error>, Box<Error>, Box<Error>),
+    /// The report lists each source that was tried, why it was skipped or failed, and the
+    /// paths and URLs it checked. It is included in the error message.
+    #[error("{0}")]
+    NoAuthMethod(Box<ChainReport>),
 
     /// Could not connect to  server
     #[error("{0}")]
@@ -212,4 +352,132 @@ pub enum Error {
 
     #[error("{0}")]
     Str(&'static str),
+
+    /// An error from a token request made by a provider's token cache
+    ///
+    /// Errors from [`TokenProvider::token()`] are always wrapped in this variant, since the
+    /// error is shared by all concurrent callers waiting for the same token. Use the accessors
+    /// [`Error::is_timeout()`], [`Error::is_retryable()`], [`Error::is_invalid_credentials()`]
+    /// and [`Error::response()`] to inspect it, rather than matching on the variant.
+    #[error(transparent)]
+    Shared(Arc<Error>),
+
+    /// The server responded to a token request with an error
+    #[error(transparent)]
+    Response(ResponseError),
+
+    /// Connecting, a request or requesting a token took longer than the configured timeout
+    ///
+    /// Errors returned by providers may wrap this in [`Error::Shared`], so use
+    /// [`Error::is_timeout()`] to check for timeouts.
+    #[error("{0}")]
+    Timeout(&'static str),
+
+    /// A [`Transport`] failed to send a request or to receive the response
+    ///
+    /// These errors are assumed to be transient, like a reset connection, and are retried.
+    #[error("{0}: {1}")]
+    Transport(
+        &'static str,
+        #[source] Box<dyn std::error::Error + Send + Sync>,
+    ),
+}
+
+impl Error {
+    /// Whether the failed operation may succeed if retried
+    ///
+    /// This is the case for connection errors and timeouts, and for error responses
+    /// indicating timeouts, rate limiting or server errors.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Response(err) => err.is_retryable(),
+            Self::Shared(err) => err.is_retryable(),
+            Self::Http(..) | Self::Timeout(_) | Self::Transport(..) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the operation timed out, see [`Error::Timeout`]
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            Self::Timeout(_) => true,
+            Self::Shared(err) => err.is_timeout(),
+            _ => false,
+        }
+    }
+
+    /// Whether the credentials were rejected by the server
+    ///
+    /// Retrying will not help, the credentials need to be replaced.
+    pub fn is_invalid_credentials(&self) -> bool {
+        match self {
+            Self::Response(err) => err.is_invalid_credentials(),
+            Self::Shared(err) => err.is_invalid_credentials(),
+            _ => false,
+        }
+    }
+
+    /// The error response from the server, if any
+    pub fn response(&self) -> Option<&ResponseError> {
+        match self {
+            Self::Response(err) => Some(err),
+            Self::Shared(err) => err.response(),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct StaticProvider(Option<Arc<str>>);
+
+    #[async_trait]
+    impl TokenProvider for StaticProvider {
+        async fn token(&self, _scopes: &[&str]) -> Result<Arc<Token>, Error> {
+            Ok(Arc::new(Token::from_string(
+                "abc123".to_owned(),
+                Duration::from_secs(3600),
+            )))
+        }
+
+        async fn project_id(&self) -> Result<Arc<str>, Error> {
+            Err(Error::Str("no project ID"))
+        }
+
+        async fn quota_project_id(&self) -> Option<Arc<str>> {
+            self.0.clone()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_headers() {
+        let headers = StaticProvider(Some(Arc::from("my-project")))
+            .headers(&[])
+            .await
+            .unwrap();
+        assert_eq!(headers[AUTHORIZATION], "Bearer abc123");
+        assert!(headers[AUTHORIZATION].is_sensitive());
+        assert_eq!(headers[QUOTA_PROJECT_HEADER], "my-project");
+
+        let headers = StaticProvider(None).headers(&[]).await.unwrap();
+        assert_eq!(headers.len(), 1);
+        assert!(!headers.contains_key(QUOTA_PROJECT_HEADER));
+    }
+
+    #[tokio::test]
+    async fn test_token_valid_for() {
+        let provider = StaticProvider(None);
+        let token = provider
+            .token_valid_for(&[], Duration::from_secs(600))
+            .await
+            .unwrap();
+        assert_eq!(token.as_str(), "abc123");
+
+        let result = provider
+            .token_valid_for(&[], Duration::from_secs(7200))
+            .await;
+        assert!(result.is_err());
+    }
 }
@@ -1,24 +1,25 @@
 use std::str;
 use std::sync::Arc;
+use std::time::Duration;
 
 use async_trait::async_trait;
 use bytes::Bytes;
-use http_body_util::Full;
 use hyper::{Method, Request};
-use tokio::sync::RwLock;
+use tokio::sync::OnceCell;
 use tracing::{debug, instrument, Level};
 
+use crate::cache::{BackgroundRefresh, TokenCache, TokenSource};
 use crate::types::{HttpClient, Token};
-use crate::{Error, TokenProvider};
+use crate::{Clock, Error, TokenProvider};
 
 /// A token provider that queries the GCP instance metadata server for access tokens
 ///
 /// See https://cloud.google.com/compute/docs/metadata/predefined-metadata-keys for details.
 #[derive(Debug)]
 pub struct MetadataServiceAccount {
-    client: HttpClient,
-    project_id: Arc<str>,
-    token: RwLock<Arc<Token>>,
+    source: Arc<MetadataSource>,
+    project_id: OnceCell<Arc<str>>,
+    token: TokenCache<()>,
 }
 
 impl MetadataServiceAccount {
@@ -28,75 +29,126 @@ impl MetadataServiceAccount {
         Self::with_client(&client).await
     }
 
-    pub(crate) async fn with_client(client: &HttpClient) -> Result<Self, Error> {
+    /// Like [`MetadataServiceAccount::new()`], sending requests with the given [`HttpClient`]
+    pub async fn with_client(client: &HttpClient) -> Result<Self, Error> {
         debug!("try to fetch token from GCP instance metadata server");
-        let token = RwLock::new(Self::fetch_token(client).await?);
-
-        debug!("getting project ID from GCP instance metadata server");
-        let req = metadata_request(DEFAULT_PROJECT_ID_GCP_URI);
-        let body = client.request(req, "MetadataServiceAccount").await?;
-        let project_id = match str::from_utf8(&body) {
-            Ok(s) if !s.is_empty() => Arc::from(s),
-            Ok(_) => {
-                return Err(Error::Str(
-                    "empty project ID from GCP instance metadata server",
-                ))
-            }
-            Err(_) => {
-                return Err(Error::Str(
-                    "received invalid UTF-8 project ID from GCP instance metadata server",
-                ))
-            }
-        };
-
-        Ok(Self {
+        let provider = Self::lazy(client);
+        provider.token.get(()).await?;
+        provider.project_id().await?;
+        Ok(provider)
+    }
+
+    /// Like [`MetadataServiceAccount::with_client()`], but without sending any requests
+    ///
+    /// The token and the project ID are only requested from the metadata server when they are
+    /// needed, so this does not check that the metadata server is available.
+    pub fn lazy(client: &HttpClient) -> Self {
+        let source = Arc::new(MetadataSource {
             client: client.clone(),
-            project_id,
-            token,
-        })
+        });
+        Self {
+            token: TokenCache::new(source.clone()),
+            source,
+            project_id: OnceCell::new(),
+        }
     }
 
-    #[instrument(level = Level::DEBUG, skip(client))]
-    async fn fetch_token(client: &HttpClient) -> Result<Arc<Token>, Error> {
-        client
-            .token(
-                &|| metadata_request(DEFAULT_TOKEN_GCP_URI),
-                "MetadataServiceAccount",
-            )
-            .await
+    /// Refresh the cached token once it expires within the given margin (default 20 seconds)
+    pub fn with_expiry_margin(mut self, margin: Duration) -> Self {
+        self.token.set_expiry_margin(margin);
+        self
+    }
+
+    /// Refresh the cached token in the background before it expires
+    pub fn with_background_refresh(mut self, refresh: BackgroundRefresh) -> Self {
+        self.token.set_background_refresh(refresh);
+        self
+    }
+
+    /// Use the given clock to determine when the cached token expires
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.token.set_clock(clock);
+        self
     }
 }
 
 #[async_trait]
 impl TokenProvider for MetadataServiceAccount {
     async fn token(&self, _scopes: &[&str]) -> Result<Arc<Token>, Error> {
-        let token = self.token.read().await.clone();
-        if !token.has_expired() {
-            return Ok(token);
-        }
+        self.token.get(()).await
+    }
+
+    async fn token_valid_for(
+        &self,
+        _scopes: &[&str],
+        min_lifetime: Duration,
+    ) -> Result<Arc<Token>, Error> {
+        self.token.get_valid_for((), min_lifetime).await
+    }
 
-        let mut locked = self.token.write().await;
-        let token = Self::fetch_token(&self.client).await?;
-        *locked = token.clone();
-        Ok(token)
+    async fn invalidate(&self, _scopes: &[&str]) {
+        self.token.invalidate(&());
+    }
+
+    async fn invalidate_all(&self) {
+        self.token.invalidate_all();
     }
 
     async fn project_id(&self) -> Result<Arc<str>, Error> {
-        Ok(self.project_id.clone())
+        self.project_id
+            .get_or_try_init(|| async {
+                debug!("getting project ID from GCP instance metadata server");
+                let req = metadata_request(DEFAULT_PROJECT_ID_GCP_URI);
+                let body = self
+                    .source
+                    .client
+                    .request(req, "MetadataServiceAccount")
+                    .await?;
+                match str::from_utf8(&body) {
+                    Ok(s) if !s.is_empty() => Ok(Arc::from(s)),
+                    Ok(_) => Err(Error::Str(
+                        "empty project ID from GCP instance metadata server",
+                    )),
+                    Err(_) => Err(Error::Str(
+                        "received invalid UTF-8 project ID from GCP instance metadata server",
+                    )),
+                }
+            })
+            .await
+            .cloned()
+    }
+}
+
+#[derive(Debug)]
+struct MetadataSource {
+    client: HttpClient,
+}
+
+#[async_trait]
+impl TokenSource<()> for MetadataSource {
+    #[instrument(level = Level::DEBUG, skip(self))]
+    async fn fetch(&self, _: &(), clock: &dyn Clock) -> Result<Arc<Token>, Error> {
+        self.client
+            .token(
+                &|| metadata_request(DEFAULT_TOKEN_GCP_URI),
+                "MetadataServiceAccount",
+                clock,
+            )
+            .await
     }
 }
 
-fn metadata_request(uri: &str) -> Request<Full<Bytes>> {
+fn metadata_request(uri: &str) -> Request<Bytes> {
     Request::builder()
         .method(Method::GET)
         .uri(uri)
         .header("Metadata-Flavor", "Google")
-        .body(Full::from(Bytes::new()))
+        .body(Bytes::new())
         .unwrap()
 }
 
 // https://cloud.google.com/compute/docs/metadata/predefined-metadata-keys
 const DEFAULT_PROJECT_ID_GCP_URI: &str =
     "http://metadata.google.internal/computeMetadata/v1/project/project-id";
-const DEFAULT_TOKEN_GCP_URI: &str =
+pub(crate) const DEFAULT_TOKEN_GCP_URI: &str =
     "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token";
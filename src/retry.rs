@@ -0,0 +1,131 @@
+use std::time::Duration;
+
+use crate::types::random_fraction;
+use crate::Error;
+
+/// Policy for retrying failed token requests
+///
+/// Only errors for which [`Error::is_retryable()`] returns `true` are retried, so requests
+/// rejected because of invalid credentials fail immediately. Between attempts, the client
+/// waits for a random duration between zero and an exponentially growing backoff ("full
+/// jitter"). If the server responds with `429 Too Many Requests` or `503 Service Unavailable`
+/// and a `Retry-After` header, the client waits at least that long instead.
+///
+/// No further attempts are made once the deadline would be exceeded, measured from the
+/// start of the first attempt.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    deadline: Option<Duration>,
+}
+
+impl RetryPolicy {
+    /// A policy which never retries
+    pub fn never() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::default()
+        }
+    }
+
+    /// Make at most this many attempts, including the first one (default 5)
+    pub fn with_max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = attempts.max(1);
+        self
+    }
+
+    /// Grow the backoff from `initial` up to `max` (default 50ms to 5s)
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max.max(initial);
+        self
+    }
+
+    /// Give up retrying once this much time has passed since the first attempt (default 30s)
+    pub fn with_deadline(mut self, deadline: Option<Duration>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// How long to wait before the next attempt, or `None` to stop retrying
+    ///
+    /// `attempt` is the number of attempts made so far and `elapsed` the time since the
+    /// first attempt started.
+    pub(crate) fn delay(&self, err: &Error, attempt: u32, elapsed: Duration) -> Option<Duration> {
+        if attempt >= self.max_attempts || !err.is_retryable() {
+            return None;
+        }
+
+        let backoff = self.backoff(attempt).mul_f64(random_fraction());
+        let delay = match err.response().and_then(|response| response.retry_after()) {
+            Some(retry_after) => retry_after.max(backoff),
+            None => backoff,
+        };
+
+        match self.deadline {
+            Some(deadline) if elapsed + delay >= deadline => None,
+            _ => Some(delay),
+        }
+    }
+
+    /// The upper bound of the delay after `attempt` attempts
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(5),
+            deadline: Some(Duration::from_secs(30)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::StatusCode;
+
+    use super::*;
+    use crate::ResponseError;
+
+    fn response(status: StatusCode, retry_after: Option<Duration>) -> Error {
+        Error::Response(ResponseError::new("test", status, None, retry_after))
+    }
+
+    #[test]
+    fn test_delay() {
+        let policy = RetryPolicy::default();
+        let err = response(StatusCode::INTERNAL_SERVER_ERROR, None);
+        for (attempt, max) in [(1, 50), (2, 100), (3, 200), (4, 400)] {
+            let delay = policy.delay(&err, attempt, Duration::ZERO).unwrap();
+            assert!(delay <= Duration::from_millis(max));
+        }
+        assert_eq!(policy.delay(&err, 5, Duration::ZERO), None);
+
+        assert_eq!(RetryPolicy::never().delay(&err, 1, Duration::ZERO), None);
+
+        let err = response(StatusCode::BAD_REQUEST, None);
+        assert_eq!(policy.delay(&err, 1, Duration::ZERO), None);
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let policy = RetryPolicy::default();
+        let err = response(StatusCode::TOO_MANY_REQUESTS, Some(Duration::from_secs(10)));
+        let delay = policy.delay(&err, 1, Duration::ZERO).unwrap();
+        assert_eq!(delay, Duration::from_secs(10));
+
+        // Waiting would exceed the deadline
+        assert_eq!(policy.delay(&err, 1, Duration::from_secs(25)), None);
+        let policy = policy.with_deadline(None);
+        assert!(policy.delay(&err, 1, Duration::from_secs(25)).is_some());
+    }
+}
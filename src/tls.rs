@@ -0,0 +1,269 @@
+use std::env;
+use std::fs;
+use std::sync::Arc;
+
+use rustls::crypto::CryptoProvider;
+use rustls::{ClientConfig, RootCertStore};
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::CertificateDer;
+use tracing::debug;
+
+use crate::{ClientCertificate, Error};
+
+/// Build the TLS configuration, trusting the default roots and `extra_roots`
+///
+/// If `SSL_CERT_FILE` is set, the certificates in that file replace the default roots.
+pub(crate) fn client_config(
+    extra_roots: &[CertificateDer<'static>],
+    client_certificate: Option<&ClientCertificate>,
+) -> Result<ClientConfig, Error> {
+    let mut roots = match env::var_os(ENV_CERT_FILE) {
+        Some(path) => {
+            debug!(?path, "loading TLS root certificates from {ENV_CERT_FILE}");
+            let pem = fs::read(&path)
+                .map_err(|err| Error::Io("failed to read TLS root certificates", err))?;
+            let mut roots = RootCertStore::empty();
+            for cert in parse_pem(&pem)? {
+                roots
+                    .add(cert)
+                    .map_err(|err| Error::Other("invalid TLS root certificate", Box::new(err)))?;
+            }
+            roots
+        }
+        None => default_roots()?,
+    };
+
+    for cert in extra_roots {
+        roots
+            .add(cert.clone())
+            .map_err(|err| Error::Other("invalid TLS root certificate", Box::new(err)))?;
+    }
+
+    let builder = ClientConfig::builder_with_provider(default_provider()?)
+        .with_safe_default_protocol_versions()
+        .map_err(|err| Error::Other("failed to initialize TLS configuration", Box::new(err)))?
+        .with_root_certificates(roots);
+
+    match client_certificate {
+        Some(cert) => builder
+            .with_client_auth_cert(
+                cert.certificate_chain().to_vec(),
+                cert.private_key().clone_key(),
+            )
+            .map_err(|err| Error::Other("invalid client certificate", Box::new(err))),
+        None => Ok(builder.with_no_client_auth()),
+    }
+}
+
+/// Parse all certificates from PEM data
+pub(crate) fn parse_pem(pem: &[u8]) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let certs = CertificateDer::pem_slice_iter(pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| Error::Other("failed to parse PEM certificates", Box::new(err)))?;
+    match certs.is_empty() {
+        true => Err(Error::Str("no certificates found in PEM data")),
+        false => Ok(certs),
+    }
+}
+
+/// The bundled Mozilla root certificates
+#[cfg(feature = "webpki-roots")]
+fn default_roots() -> Result<RootCertStore, Error> {
+    Ok(RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    })
+}
+
+/// The platform's native root certificates
+#[cfg(not(feature = "webpki-roots"))]
+fn default_roots() -> Result<RootCertStore, Error> {
+    let result = rustls_native_certs::load_native_certs();
+    for err in &result.errors {
+        tracing::warn!(%err, "failed to load native TLS root certificate");
+    }
+
+    let mut roots = RootCertStore::empty();
+    let (valid, invalid) = roots.add_parsable_certificates(result.certs);
+    debug!(valid, invalid, "loaded native TLS root certificates");
+    match roots.is_empty() {
+        true => Err(Error::Io(
+            "failed to load native TLS root certificates for HTTPS",
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "no valid native root CA certificates found",
+            ),
+        )),
+        false => Ok(roots),
+    }
+}
+
+/// Returns the bundled `ring` [`CryptoProvider`] backing the TLS configuration.
+#[cfg(feature = "ring")]
+fn default_provider() -> Result<Arc<CryptoProvider>, Error> {
+    Ok(Arc::new(rustls::crypto::ring::default_provider()))
+}
+
+/// Returns the bundled `aws-lc-rs` [`CryptoProvider`] backing the TLS configuration.
+#[cfg(all(not(feature = "ring"), feature = "aws-lc-rs"))]
+fn default_provider() -> Result<Arc<CryptoProvider>, Error> {
+    Ok(Arc::new(rustls::crypto::aws_lc_rs::default_provider()))
+}
+
+/// Returns the per-process default [`CryptoProvider`] backing the TLS configuration.
+///
+/// With no crypto feature enabled, the provider is whatever the application has
+/// installed as the per-process default via [`CryptoProvider::install_default()`].
+#[cfg(not(any(feature = "ring", feature = "aws-lc-rs")))]
+fn default_provider() -> Result<Arc<CryptoProvider>, Error> {
+    CryptoProvider::get_default().cloned().ok_or(Error::Str(
+        "no per-process default crypto provider installed: enable the `ring` or `aws-lc-rs` \
+         feature, or install one with `CryptoProvider::install_default()`",
+    ))
+}
+
+const ENV_CERT_FILE: &str = "SSL_CERT_FILE";
+
+#[cfg(all(test, feature = "ring"))]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+
+    use bytes::Bytes;
+    use http_body_util::Full;
+    use hyper::server::conn::http2;
+    use hyper::service::service_fn;
+    use hyper::{Request, Response};
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use rustls::server::WebPkiClientVerifier;
+    use rustls::ServerConfig;
+    use rustls_pki_types::PrivateKeyDer;
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    use super::*;
+    use crate::{HttpClient, RetryPolicy, SystemClock};
+
+    #[tokio::test]
+    async fn test_root_certificates() {
+        let addr = serve(false).await;
+        let request = || {
+            Request::get(format!("https://localhost:{}/token", addr.port()))
+                .body(Bytes::new())
+                .unwrap()
+        };
+
+        let client = HttpClient::builder()
+            .with_proxy(None)
+            .with_retry_policy(RetryPolicy::never())
+            .with_root_certificates_pem(CA)
+            .unwrap()
+            .build()
+            .unwrap();
+        let token = client.token(&request, "test", &SystemClock).await.unwrap();
+        assert_eq!(token.as_str(), "abc123");
+
+        // The test CA is not trusted by default
+        let client = HttpClient::builder()
+            .with_proxy(None)
+            .with_retry_policy(RetryPolicy::never())
+            .build()
+            .unwrap();
+        assert!(client.token(&request, "test", &SystemClock).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_client_certificate() {
+        let addr = serve(true).await;
+        let request = || {
+            Request::get(format!("https://localhost:{}/token", addr.port()))
+                .body(Bytes::new())
+                .unwrap()
+        };
+
+        let cert = ClientCertificate::from_pem(
+            include_bytes!("../testdata/client.pem"),
+            include_bytes!("../testdata/client.key"),
+        )
+        .unwrap();
+        let builder = HttpClient::builder()
+            .with_proxy(None)
+            .with_retry_policy(RetryPolicy::never())
+            .with_root_certificates_pem(CA)
+            .unwrap();
+
+        let client = builder
+            .clone()
+            .with_client_certificate(Some(cert))
+            .build()
+            .unwrap();
+        assert!(client.is_mtls());
+        assert_eq!(
+            client.endpoint("https://oauth2.googleapis.com/token"),
+            "https://oauth2.mtls.googleapis.com/token"
+        );
+        let token = client.token(&request, "test", &SystemClock).await.unwrap();
+        assert_eq!(token.as_str(), "abc123");
+
+        // The server rejects clients without a certificate
+        let client = builder.with_client_certificate(None).build().unwrap();
+        assert!(!client.is_mtls());
+        assert!(client.token(&request, "test", &SystemClock).await.is_err());
+    }
+
+    #[test]
+    fn test_parse_pem() {
+        assert_eq!(parse_pem(CA).unwrap().len(), 1);
+        assert!(parse_pem(b"not a certificate").is_err());
+    }
+
+    /// Serve a token response over TLS, using a certificate for `localhost` signed by [`CA`]
+    ///
+    /// With `client_auth`, clients must present a certificate signed by [`CA`].
+    async fn serve(client_auth: bool) -> SocketAddr {
+        let certs = parse_pem(include_bytes!("../testdata/server.pem")).unwrap();
+        let key = PrivateKeyDer::from_pem_slice(include_bytes!("../testdata/server.key")).unwrap();
+        let provider = default_provider().unwrap();
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .unwrap();
+        let builder = match client_auth {
+            true => {
+                let mut roots = RootCertStore::empty();
+                roots.add_parsable_certificates(parse_pem(CA).unwrap());
+                let verifier = WebPkiClientVerifier::builder_with_provider(roots.into(), provider)
+                    .build()
+                    .unwrap();
+                builder.with_client_cert_verifier(verifier)
+            }
+            false => builder.with_no_client_auth(),
+        };
+        let mut config = builder.with_single_cert(certs, key).unwrap();
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let Ok(stream) = acceptor.accept(stream).await else {
+                    continue;
+                };
+
+                let service = service_fn(|_| async {
+                    Ok::<_, Infallible>(Response::new(Full::new(Bytes::from_static(
+                        br#"{"access_token":"abc123","expires_in":3600}"#,
+                    ))))
+                });
+                tokio::spawn(
+                    http2::Builder::new(TokioExecutor::new())
+                        .serve_connection(TokioIo::new(stream), service),
+                );
+            }
+        });
+
+        addr
+    }
+
+    const CA: &[u8] = include_bytes!("../testdata/ca.pem");
+}
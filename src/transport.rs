@@ -0,0 +1,301 @@
+use std::fmt;
+use std::io;
+use std::mem;
+use std::sync::{Mutex, PoisonError};
+
+use async_trait::async_trait;
+use bytes::{Buf, Bytes};
+use http_body_util::{BodyExt, Full};
+use hyper::{Request, Response};
+use hyper_rustls::HttpsConnector;
+use hyper_util::client::legacy::Client;
+
+use crate::proxy::ProxyConnector;
+use crate::Error;
+
+/// Sends HTTP requests on behalf of an [`HttpClient`](crate::HttpClient)
+///
+/// By default, requests are sent with a `hyper` client using `rustls`. Implement this trait
+/// to send requests through an existing client instead, for example to share its connection
+/// pool or to instrument requests, and pass it to
+/// [`HttpClientBuilder::build_with_transport()`](crate::HttpClientBuilder::build_with_transport).
+///
+/// Implementations should return responses with any status; error statuses are handled by
+/// the [`HttpClient`](crate::HttpClient). Failures to send the request or to read the response
+/// should be reported as [`Error::Transport`], or as [`Error::Timeout`] if the request timed
+/// out, so that they are retried. Other errors are not retried.
+#[async_trait]
+pub trait Transport: fmt::Debug + Send + Sync {
+    /// Send the request and read the response body
+    async fn send(&self, request: Request<Bytes>) -> Result<Response<Bytes>, Error>;
+}
+
+/// The default [`Transport`], using a `hyper` client
+#[derive(Debug)]
+pub(crate) struct HyperTransport {
+    client: Client<HttpsConnector<ProxyConnector>, Full<Bytes>>,
+}
+
+impl HyperTransport {
+    pub(crate) fn new(client: Client<HttpsConnector<ProxyConnector>, Full<Bytes>>) -> Self {
+        Self { client }
+    }
+}
+
+#[async_trait]
+impl Transport for HyperTransport {
+    async fn send(&self, request: Request<Bytes>) -> Result<Response<Bytes>, Error> {
+        let (parts, body) = self
+            .client
+            .request(request.map(Full::new))
+            .await
+            .map_err(|err| match is_timeout(&err) {
+                true => Error::Timeout("connecting to the server timed out"),
+                false => Error::Transport("HTTP request failed", Box::new(err)),
+            })?
+            .into_parts();
+
+        let mut body = body
+            .collect()
+            .await
+            .map_err(|err| Error::Http("failed to read HTTP response body", err))?
+            .aggregate();
+
+        Ok(Response::from_parts(
+            parts,
+            body.copy_to_bytes(body.remaining()),
+        ))
+    }
+}
+
+/// Whether the error was caused by a connect timeout
+fn is_timeout(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<io::Error>() {
+            return err.kind() == io::ErrorKind::TimedOut;
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// A [`Transport`] which answers requests in memory, for use in tests
+///
+/// Responses are produced by the given handler. All requests are recorded and can be
+/// taken with [`InMemoryTransport::take_requests()`].
+///
+/// ```
+/// use std::sync::Arc;
+///
+/// use bytes::Bytes;
+/// use gcp_auth::{HttpClient, InMemoryTransport};
+/// use http::Response;
+///
+/// let transport = Arc::new(InMemoryTransport::new(|_| {
+///     Ok(Response::new(Bytes::from_static(
+///         br#"{"access_token":"abc123","expires_in":3600}"#,
+///     )))
+/// }));
+/// let client = HttpClient::builder().build_with_transport(transport.clone());
+/// ```
+pub struct InMemoryTransport {
+    handler: Box<Handler>,
+    requests: Mutex<Vec<Request<Bytes>>>,
+}
+
+impl InMemoryTransport {
+    /// Answer requests with the given handler
+    pub fn new(
+        handler: impl Fn(&Request<Bytes>) -> Result<Response<Bytes>, Error> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            handler: Box::new(handler),
+            requests: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Take the requests sent since the last call
+    pub fn take_requests(&self) -> Vec<Request<Bytes>> {
+        mem::take(&mut *self.requests.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+}
+
+#[async_trait]
+impl Transport for InMemoryTransport {
+    async fn send(&self, request: Request<Bytes>) -> Result<Response<Bytes>, Error> {
+        let response = (self.handler)(&request);
+        self.requests
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(request);
+        response
+    }
+}
+
+impl fmt::Debug for InMemoryTransport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InMemoryTransport")
+            .field(
+                "requests",
+                &self
+                    .requests
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .len(),
+            )
+            .finish_non_exhaustive()
+    }
+}
+
+type Handler = dyn Fn(&Request<Bytes>) -> Result<Response<Bytes>, Error> + Send + Sync;
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::sync::Arc;
+
+    use hyper::StatusCode;
+
+    use super::*;
+    use crate::{
+        AuthorizedUserRefreshToken, ConfigDefaultCredentials, HttpClient, RetryPolicy,
+        TokenProvider,
+    };
+
+    #[tokio::test]
+    async fn test_in_memory() {
+        let transport = Arc::new(InMemoryTransport::new(|_| {
+            Ok(Response::new(Bytes::from_static(
+                br#"{"access_token":"abc123","expires_in":3600}"#,
+            )))
+        }));
+        let client = HttpClient::builder().build_with_transport(transport.clone());
+        let credentials = AuthorizedUserRefreshToken::from_str(CREDENTIALS).unwrap();
+        let provider = ConfigDefaultCredentials::with_credentials(credentials, &client)
+            .await
+            .unwrap();
+
+        let token = provider.token(&[]).await.unwrap();
+        assert_eq!(token.as_str(), "abc123");
+
+        let requests = transport.take_requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(
+            requests[0].uri(),
+            "https://accounts.google.com/o/oauth2/token"
+        );
+        let body = serde_json::from_slice::<serde_json::Value>(requests[0].body()).unwrap();
+        assert_eq!(body["refresh_token"], "refresh");
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_error() {
+        let transport = Arc::new(InMemoryTransport::new(|_| {
+            let mut response = Response::new(Bytes::from_static(br#"{"error":"invalid_grant"}"#));
+            *response.status_mut() = StatusCode::BAD_REQUEST;
+            Ok(response)
+        }));
+        let client = HttpClient::builder()
+            .with_retry_policy(RetryPolicy::never())
+            .build_with_transport(transport.clone());
+        let credentials = AuthorizedUserRefreshToken::from_str(CREDENTIALS).unwrap();
+        let err = ConfigDefaultCredentials::with_credentials(credentials, &client)
+            .await
+            .unwrap_err();
+
+        assert!(err.is_invalid_credentials());
+        assert_eq!(transport.take_requests().len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_in_memory_retry() {
+        let transport = Arc::new(InMemoryTransport::new(|_| {
+            Err(Error::Transport(
+                "connection reset",
+                Box::new(io::Error::from(io::ErrorKind::ConnectionReset)),
+            ))
+        }));
+        let client = HttpClient::builder()
+            .with_retry_policy(RetryPolicy::default().with_max_attempts(3))
+            .build_with_transport(transport.clone());
+        let credentials = AuthorizedUserRefreshToken::from_str(CREDENTIALS).unwrap();
+        let err = ConfigDefaultCredentials::with_credentials(credentials, &client)
+            .await
+            .unwrap_err();
+
+        assert!(err.is_retryable());
+        assert_eq!(transport.take_requests().len(), 3);
+    }
+
+    #[cfg(any(feature = "ring", feature = "aws-lc-rs"))]
+    #[tokio::test]
+    async fn test_incomplete_message() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::time::Duration;
+
+        use tokio::io::AsyncReadExt;
+        use tokio::net::TcpListener;
+
+        use crate::SystemClock;
+
+        // The server closes each connection without responding
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counter = connections.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                let _ = stream.read(&mut [0; 1024]).await;
+            }
+        });
+
+        let client = HttpClient::builder()
+            .with_proxy(None)
+            .with_retry_policy(
+                RetryPolicy::default()
+                    .with_max_attempts(2)
+                    .with_backoff(Duration::from_millis(1), Duration::from_millis(1)),
+            )
+            .build()
+            .unwrap();
+        let request = || {
+            Request::get(format!("http://{addr}/token"))
+                .body(Bytes::new())
+                .unwrap()
+        };
+        let err = client
+            .token(&request, "test", &SystemClock)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, Error::Transport(..)), "{err:?}");
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_timeout() {
+        let transport = Arc::new(InMemoryTransport::new(|_| {
+            Err(Error::Timeout("request timed out"))
+        }));
+        let client = HttpClient::builder()
+            .with_retry_policy(RetryPolicy::never())
+            .build_with_transport(transport);
+        let credentials = AuthorizedUserRefreshToken::from_str(CREDENTIALS).unwrap();
+        let provider = ConfigDefaultCredentials::lazy_with_credentials(credentials, &client);
+
+        let err = provider.token(&[]).await.unwrap_err();
+        assert!(err.is_timeout(), "{err:?}");
+        assert!(err.is_retryable());
+    }
+
+    const CREDENTIALS: &str = r#"{
+        "client_id": "client",
+        "client_secret": "secret",
+        "quota_project_id": "project",
+        "refresh_token": "refresh",
+        "type": "authorized_user"
+    }"#;
+}
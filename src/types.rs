@@ -1,142 +1,476 @@
+use std::borrow::Cow;
+use std::collections::hash_map::RandomState;
 #[cfg(any(feature = "ring", feature = "aws-lc-rs"))]
 use std::env;
 use std::fmt;
 use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
 use std::path::Path;
-#[cfg(any(feature = "ring", feature = "aws-lc-rs"))]
 use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
 
-use bytes::Buf;
 use chrono::{DateTime, Utc};
-use http_body_util::{BodyExt, Full};
 use hyper::body::Bytes;
-use hyper::Request;
+use hyper::header::RETRY_AFTER;
+use hyper::{Request, StatusCode};
 use hyper_rustls::HttpsConnectorBuilder;
+use hyper_util::client::legacy::connect::HttpConnector;
 use hyper_util::client::legacy::Client;
 use hyper_util::rt::TokioExecutor;
-use rustls::crypto::CryptoProvider;
-use serde::{Deserialize, Deserializer};
-use tokio::time::sleep;
+use rustls_pki_types::CertificateDer;
+use serde::{Deserialize, Serialize};
+use tokio::time::{sleep, Instant};
 use tracing::{debug, warn};
 
-use crate::Error;
+use crate::client_certificate::mtls_endpoint;
+use crate::proxy::ProxyConnector;
+use crate::tls;
+use crate::transport::HyperTransport;
+use crate::{ClientCertificate, Clock, Error, Proxy, RetryPolicy, Transport};
 
+/// HTTP client used by token providers to request tokens
+///
+/// A single client can be shared between providers to reuse its connection pool.
 #[derive(Clone, Debug)]
-pub(crate) struct HttpClient {
-    inner: Client<
-        hyper_rustls::HttpsConnector<hyper_util::client::legacy::connect::HttpConnector>,
-        Full<Bytes>,
-    >,
+pub struct HttpClient {
+    transport: Arc<dyn Transport>,
+    retry: RetryPolicy,
+    request_timeout: Option<Duration>,
+    timeout: Option<Duration>,
+    mtls: bool,
 }
 
 impl HttpClient {
-    pub(crate) fn new() -> Result<Self, Error> {
-        #[cfg(feature = "webpki-roots")]
-        let https = HttpsConnectorBuilder::new()
-            .with_provider_and_webpki_roots(default_provider()?)
-            .map_err(|err| Error::Other("failed to initialize TLS configuration", Box::new(err)))?;
-        #[cfg(not(feature = "webpki-roots"))]
-        let https = HttpsConnectorBuilder::new()
-            .with_provider_and_native_roots(default_provider()?)
-            .map_err(|err| {
-                Error::Io("failed to load native TLS root certificates for HTTPS", err)
-            })?;
-
-        Ok(Self {
-            inner: Client::builder(TokioExecutor::new())
-                .build(https.https_or_http().enable_http2().build()),
-        })
+    /// Create a new client with the default configuration, loading the TLS root certificates
+    ///
+    /// Uses the platform's native root certificates, or the bundled Mozilla root certificates
+    /// if the `webpki-roots` feature is enabled. If the `SSL_CERT_FILE` environment variable
+    /// is set, the certificates in that PEM file are trusted instead.
+    pub fn new() -> Result<Self, Error> {
+        Self::builder().build()
+    }
+
+    /// Configure a new client
+    pub fn builder() -> HttpClientBuilder {
+        HttpClientBuilder::default()
+    }
+
+    /// Retry failed token requests according to the given policy
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Whether requests are authenticated with a client certificate
+    pub fn is_mtls(&self) -> bool {
+        self.mtls
+    }
+
+    /// The endpoint to send requests for `uri` to, using the mTLS variant if applicable
+    pub(crate) fn endpoint<'a>(&self, uri: &'a str) -> Cow<'a, str> {
+        match self.mtls {
+            true => mtls_endpoint(uri),
+            false => Cow::Borrowed(uri),
+        }
     }
 
     pub(crate) async fn token(
         &self,
-        request: &impl Fn() -> Request<Full<Bytes>>,
+        request: &impl Fn() -> Request<Bytes>,
         provider: &'static str,
+        clock: &dyn Clock,
     ) -> Result<Arc<Token>, Error> {
-        //We multiply it by two on every iteration to progressively slow down ourself
-        //At most we will perform 50 + 100 + 200 + 400 wait as we're limited by 4 re-tries
-        let mut sleep_interval = Duration::from_millis(50);
-        let mut retries = 0;
+        let token = self.token_with_retries(request, provider, clock);
+        match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, token)
+                .await
+                .map_err(|_| Error::Timeout("token request deadline exceeded"))?,
+            None => token.await,
+        }
+    }
 
+    async fn token_with_retries(
+        &self,
+        request: &impl Fn() -> Request<Bytes>,
+        provider: &'static str,
+        clock: &dyn Clock,
+    ) -> Result<Arc<Token>, Error> {
+        let start = Instant::now();
+        let mut attempts = 0;
         let body = loop {
+            attempts += 1;
             let err = match self.request(request(), provider).await {
                 // Early return when the request succeeds
                 Ok(body) => break body,
                 Err(err) => err,
             };
 
+            let Some(delay) = self.retry.delay(&err, attempts, start.elapsed()) else {
+                return Err(err);
+            };
+
             warn!(
                 ?err,
-                provider, retries, "failed to refresh token, trying again..."
+                provider,
+                attempts,
+                ?delay,
+                "failed to refresh token, trying again..."
             );
-
-            retries += 1;
-            if retries >= RETRY_COUNT {
-                return Err(err);
-            }
-
-            sleep(sleep_interval).await;
-            sleep_interval *= 2;
+            sleep(delay).await;
         };
 
-        serde_json::from_slice(&body)
-            .map_err(|err| Error::Json("failed to deserialize token from response", err))
+        let data = serde_json::from_slice::<TokenData>(&body)
+            .map_err(|err| Error::Json("failed to deserialize token from response", err))?;
+        let token = data.into_token(clock.now()).map_err(Error::Str)?;
+        Ok(Arc::new(token.with_provider(provider)))
     }
 
     pub(crate) async fn request(
         &self,
-        req: Request<Full<Bytes>>,
+        req: Request<Bytes>,
         provider: &'static str,
     ) -> Result<Bytes, Error> {
         debug!(url = ?req.uri(), provider, "requesting token");
-        let (parts, body) = self
-            .inner
-            .request(req)
-            .await
-            .map_err(|err| Error::Other("HTTP request failed", Box::new(err)))?
-            .into_parts();
-
-        let mut body = body
-            .collect()
-            .await
-            .map_err(|err| Error::Http("failed to read HTTP response body", err))?
-            .aggregate();
+        let response = self.transport.send(req);
+        let (parts, body) = match self.request_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, response)
+                .await
+                .map_err(|_| Error::Timeout("HTTP request timed out"))??,
+            None => response.await?,
+        }
+        .into_parts();
 
-        let body = body.copy_to_bytes(body.remaining());
         if !parts.status.is_success() {
-            let body = String::from_utf8_lossy(body.as_ref());
-            warn!(%body, status = ?parts.status, "token request failed");
-            return Err(Error::Str("token request failed"));
+            warn!(body = %String::from_utf8_lossy(body.as_ref()), status = ?parts.status, "token request failed");
+            let retry_after = match parts.status {
+                StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => parts
+                    .headers
+                    .get(RETRY_AFTER)
+                    .and_then(|value| parse_retry_after(value.to_str().ok()?, Utc::now())),
+                _ => None,
+            };
+
+            return Err(Error::Response(ResponseError::new(
+                provider,
+                parts.status,
+                OAuthError::from_slice(&body),
+                retry_after,
+            )));
         }
 
         Ok(body)
     }
 }
 
-/// Returns the bundled `ring` [`CryptoProvider`] backing the TLS configuration.
-#[cfg(feature = "ring")]
-fn default_provider() -> Result<Arc<CryptoProvider>, Error> {
-    Ok(Arc::new(rustls::crypto::ring::default_provider()))
+/// Configuration for an [`HttpClient`]
+///
+/// By default, connecting times out after 10 seconds, each request after 30 seconds and
+/// requesting a token (including any retries) after 60 seconds. Timeouts are reported as
+/// [`Error::Timeout`]. The proxy configuration is read from the environment, see [`Proxy`], as
+/// is the client certificate, see [`ClientCertificate::from_env()`].
+#[derive(Clone, Debug)]
+pub struct HttpClientBuilder {
+    proxy: Option<Proxy>,
+    tls_config: Option<rustls::ClientConfig>,
+    root_certificates: Vec<CertificateDer<'static>>,
+    client_certificate: Option<ClientCertificate>,
+    client_certificate_from_env: bool,
+    connect_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+    timeout: Option<Duration>,
+    retry: RetryPolicy,
+}
+
+impl HttpClientBuilder {
+    /// Connect through the given proxy, or directly if `None`
+    pub fn with_proxy(mut self, proxy: Option<Proxy>) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    /// Time out connecting to a server after the given duration
+    pub fn with_connect_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Time out a single request, including reading the response, after the given duration
+    pub fn with_request_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// Time out requesting a token, including any retries, after the given duration
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Retry failed token requests according to the given policy
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Use the given TLS configuration instead of the default one
+    ///
+    /// Root certificates added with [`HttpClientBuilder::with_root_certificates_pem()`] and
+    /// `SSL_CERT_FILE` do not apply to a custom configuration. Its ALPN protocols are replaced.
+    pub fn with_tls_config(mut self, config: rustls::ClientConfig) -> Self {
+        self.tls_config = Some(config);
+        self
+    }
+
+    /// Trust the root certificates in the given PEM data, in addition to the default roots
+    pub fn with_root_certificates_pem(mut self, pem: &[u8]) -> Result<Self, Error> {
+        self.root_certificates.extend(tls::parse_pem(pem)?);
+        Ok(self)
+    }
+
+    /// Trust the given root certificate, in addition to the default roots
+    pub fn with_root_certificate(mut self, cert: CertificateDer<'static>) -> Self {
+        self.root_certificates.push(cert);
+        self
+    }
+
+    /// Present the given client certificate, or none if `None`
+    ///
+    /// With a client certificate, tokens are requested from the mTLS token endpoints.
+    pub fn with_client_certificate(mut self, cert: Option<ClientCertificate>) -> Self {
+        self.client_certificate = cert;
+        self.client_certificate_from_env = false;
+        self
+    }
+
+    /// Create the client, loading the TLS root certificates
+    pub fn build(self) -> Result<HttpClient, Error> {
+        let client_certificate = match self.client_certificate_from_env {
+            true => ClientCertificate::from_env()?,
+            false => self.client_certificate.clone(),
+        };
+
+        let mut tls = match &self.tls_config {
+            Some(config) => config.clone(),
+            None => tls::client_config(&self.root_certificates, client_certificate.as_ref())?,
+        };
+        let mtls = tls.client_auth_cert_resolver.has_certs();
+        // ALPN protocols are configured by the connector
+        tls.alpn_protocols.clear();
+        let https = HttpsConnectorBuilder::new().with_tls_config(tls);
+
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        http.set_connect_timeout(self.connect_timeout);
+        let connector = ProxyConnector::new(http, self.proxy.clone());
+
+        let client = Client::builder(TokioExecutor::new()).build(
+            https
+                .https_or_http()
+                .enable_http2()
+                .wrap_connector(connector),
+        );
+        Ok(self.finish(Arc::new(HyperTransport::new(client)), mtls))
+    }
+
+    /// Create the client, sending requests with the given [`Transport`]
+    ///
+    /// The proxy, TLS and connect timeout settings do not apply to custom transports. If a
+    /// client certificate was set with [`HttpClientBuilder::with_client_certificate()`], the
+    /// mTLS token endpoints are used, and the transport is expected to present a certificate.
+    pub fn build_with_transport(self, transport: Arc<dyn Transport>) -> HttpClient {
+        let mtls = !self.client_certificate_from_env && self.client_certificate.is_some();
+        self.finish(transport, mtls)
+    }
+
+    fn finish(self, transport: Arc<dyn Transport>, mtls: bool) -> HttpClient {
+        HttpClient {
+            transport,
+            retry: self.retry,
+            request_timeout: self.request_timeout,
+            timeout: self.timeout,
+            mtls,
+        }
+    }
+}
+
+impl Default for HttpClientBuilder {
+    fn default() -> Self {
+        Self {
+            proxy: Some(Proxy::from_env()),
+            tls_config: None,
+            root_certificates: Vec::new(),
+            client_certificate: None,
+            client_certificate_from_env: true,
+            connect_timeout: Some(Duration::from_secs(10)),
+            request_timeout: Some(Duration::from_secs(30)),
+            timeout: Some(Duration::from_secs(60)),
+            retry: RetryPolicy::default(),
+        }
+    }
+}
+
+/// An error response to a token request
+#[derive(Clone, Debug)]
+pub struct ResponseError {
+    provider: &'static str,
+    status: StatusCode,
+    oauth: Option<OAuthError>,
+    retry_after: Option<Duration>,
+}
+
+impl ResponseError {
+    pub(crate) fn new(
+        provider: &'static str,
+        status: StatusCode,
+        oauth: Option<OAuthError>,
+        retry_after: Option<Duration>,
+    ) -> Self {
+        Self {
+            provider,
+            status,
+            oauth,
+            retry_after,
+        }
+    }
+
+    /// The name of the provider which made the request, like `CustomServiceAccount`
+    pub fn provider(&self) -> &'static str {
+        self.provider
+    }
+
+    /// The HTTP status of the response
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    /// The error from the response body, if it could be parsed
+    pub fn oauth_error(&self) -> Option<&OAuthError> {
+        self.oauth.as_ref()
+    }
+
+    /// How long the server asked to wait before retrying
+    ///
+    /// Taken from the `Retry-After` header of `429 Too Many Requests` and
+    /// `503 Service Unavailable` responses.
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.retry_after
+    }
+
+    /// Whether the request may succeed if retried
+    ///
+    /// This is the case for timeouts, rate limiting and server errors.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self.status,
+            StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS
+        ) || (self.status.is_server_error() && self.status != StatusCode::NOT_IMPLEMENTED)
+    }
+
+    /// Whether the credentials were rejected, for example because they were revoked or the
+    /// service account was disabled or deleted
+    pub fn is_invalid_credentials(&self) -> bool {
+        match &self.oauth {
+            Some(oauth) => matches!(
+                oauth.error.as_str(),
+                "invalid_grant" | "invalid_client" | "unauthorized_client" | "UNAUTHENTICATED"
+            ),
+            None => self.status == StatusCode::UNAUTHORIZED,
+        }
+    }
+}
+
+impl fmt::Display for ResponseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} token request failed with status {}",
+            self.provider, self.status
+        )?;
+
+        if let Some(oauth) = &self.oauth {
+            write!(f, ": {}", oauth.error)?;
+            if let Some(description) = &oauth.error_description {
+                write!(f, " ({description})")?;
+            }
+        }
+
+        Ok(())
+    }
 }
 
-/// Returns the bundled `aws-lc-rs` [`CryptoProvider`] backing the TLS configuration.
-#[cfg(all(not(feature = "ring"), feature = "aws-lc-rs"))]
-fn default_provider() -> Result<Arc<CryptoProvider>, Error> {
-    Ok(Arc::new(rustls::crypto::aws_lc_rs::default_provider()))
+impl std::error::Error for ResponseError {}
+
+/// Parse a `Retry-After` header value, either in seconds or as an HTTP date
+fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    if let Ok(seconds) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value.trim()).ok()?;
+    Some(
+        (date.with_timezone(&Utc) - now)
+            .to_std()
+            .unwrap_or_default(),
+    )
 }
 
-/// Returns the per-process default [`CryptoProvider`] backing the TLS configuration.
+/// An OAuth 2.0 error response (see [RFC 6749, section 5.2](https://www.rfc-editor.org/rfc/rfc6749#section-5.2))
 ///
-/// With no crypto feature enabled, the provider is whatever the application has
-/// installed as the per-process default via [`CryptoProvider::install_default()`].
-#[cfg(not(any(feature = "ring", feature = "aws-lc-rs")))]
-fn default_provider() -> Result<Arc<CryptoProvider>, Error> {
-    CryptoProvider::get_default().cloned().ok_or(Error::Str(
-        "no per-process default crypto provider installed: enable the `ring` or `aws-lc-rs` \
-         feature, or install one with `CryptoProvider::install_default()`",
-    ))
+/// Google API errors of the form `{"error": {"status": ..., "message": ...}}` are mapped to
+/// the `error` and `error_description` fields.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct OAuthError {
+    /// The error code, like `invalid_grant`
+    pub error: String,
+    /// A human-readable description of the error
+    pub error_description: Option<String>,
+    /// A URI of a web page with information about the error
+    pub error_uri: Option<String>,
+}
+
+impl OAuthError {
+    fn from_slice(body: &[u8]) -> Option<Self> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Response {
+            OAuth {
+                error: String,
+                error_description: Option<String>,
+                error_uri: Option<String>,
+            },
+            Google {
+                error: GoogleError,
+            },
+        }
+
+        #[derive(Deserialize)]
+        struct GoogleError {
+            status: Option<String>,
+            code: Option<u16>,
+            message: Option<String>,
+        }
+
+        Some(match serde_json::from_slice(body).ok()? {
+            Response::OAuth {
+                error,
+                error_description,
+                error_uri,
+            } => Self {
+                error,
+                error_description,
+                error_uri,
+            },
+            Response::Google { error } => Self {
+                error: error
+                    .status
+                    .or_else(|| error.code.map(|code| code.to_string()))?,
+                error_description: error.message,
+                error_uri: None,
+            },
+        })
+    }
 }
 
 /// Represents an access token that can be used as a bearer token in HTTP requests
@@ -148,29 +482,60 @@ fn default_provider() -> Result<Arc<CryptoProvider>, Error> {
 /// files, likewise [`Debug`] does not expose the token value itself which is only available
 /// using the [Token::`as_str`] method.
 ///
+/// Tokens can be deserialized from token responses as returned by the server (see
+/// <https://cloud.google.com/iam/docs/reference/sts/rest/v1/TopLevel/token#response-body>),
+/// which contain the relative `expires_in`. They serialize with the absolute `expires_at`
+/// instead, so serialized tokens can be stored or passed to other processes and deserialized
+/// again later.
+///
 /// [`AuthenticationManager`]: crate::AuthenticationManager
 /// [`Display`]: fmt::Display
-/// Token data as returned by the server
-///
-/// https://cloud.google.com/iam/docs/reference/sts/rest/v1/TopLevel/token#response-body
-#[derive(Clone, Deserialize)]
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(try_from = "TokenData")]
 pub struct Token {
     access_token: String,
-    #[serde(
-        deserialize_with = "deserialize_time",
-        rename(deserialize = "expires_in")
-    )]
     expires_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    provider: Option<String>,
 }
 
 impl Token {
-    pub(crate) fn from_string(access_token: String, expires_in: Duration) -> Self {
+    pub(crate) fn new(access_token: String, expires_at: DateTime<Utc>) -> Self {
         Token {
             access_token,
-            expires_at: Utc::now() + expires_in,
+            expires_at,
+            token_type: None,
+            scope: None,
+            id_token: None,
+            provider: None,
         }
     }
 
+    #[cfg(test)]
+    pub(crate) fn from_string(access_token: String, expires_in: Duration) -> Self {
+        Self::new(access_token, Utc::now() + expires_in)
+    }
+
+    /// Set the name of the provider which issued the token, unless already set
+    pub(crate) fn with_provider(mut self, provider: &str) -> Self {
+        if self.provider.is_none() {
+            self.provider = Some(provider.to_owned());
+        }
+        self
+    }
+
+    /// Set the token type
+    pub(crate) fn with_token_type(mut self, token_type: &str) -> Self {
+        self.token_type = Some(token_type.to_owned());
+        self
+    }
+
     /// Define if the token has has_expired
     ///
     /// This takes an additional 30s margin to ensure the token can still be reasonably used
@@ -182,7 +547,12 @@ impl Token {
     /// The docs state, the metadata server caches tokens until 5 minutes before expiry.
     /// We use 20s to be on the safe side.
     pub fn has_expired(&self) -> bool {
-        self.expires_at - Duration::from_secs(20) <= Utc::now()
+        self.expires_within(EXPIRY_MARGIN, Utc::now())
+    }
+
+    /// Whether the token expires within the given duration from `now`
+    pub(crate) fn expires_within(&self, duration: Duration, now: DateTime<Utc>) -> bool {
+        self.expires_at - duration <= now
     }
 
     /// Get str representation of the token.
@@ -194,6 +564,33 @@ impl Token {
     pub fn expires_at(&self) -> DateTime<Utc> {
         self.expires_at
     }
+
+    /// The type of the token, usually `Bearer`, if returned by the server
+    pub fn token_type(&self) -> Option<&str> {
+        self.token_type.as_deref()
+    }
+
+    /// The space-separated scopes granted for the token, if returned by the server
+    ///
+    /// These can differ from the requested scopes.
+    pub fn scope(&self) -> Option<&str> {
+        self.scope.as_deref()
+    }
+
+    /// Iterate over the scopes granted for the token
+    pub fn scopes(&self) -> impl Iterator<Item = &str> {
+        self.scope.as_deref().unwrap_or_default().split_whitespace()
+    }
+
+    /// The OpenID Connect ID token, if returned by the server
+    pub fn id_token(&self) -> Option<&str> {
+        self.id_token.as_deref()
+    }
+
+    /// The name of the provider which issued the token, like `CustomServiceAccount`
+    pub fn provider(&self) -> Option<&str> {
+        self.provider.as_deref()
+    }
 }
 
 impl fmt::Debug for Token {
@@ -201,10 +598,54 @@ impl fmt::Debug for Token {
         f.debug_struct("Token")
             .field("access_token", &"****")
             .field("expires_at", &self.expires_at)
+            .field("token_type", &self.token_type)
+            .field("scope", &self.scope)
+            .field("id_token", &self.id_token.as_ref().map(|_| "****"))
+            .field("provider", &self.provider)
             .finish()
     }
 }
 
+/// Token data as returned by the server or as serialized by [`Token`]
+#[derive(Deserialize)]
+pub(crate) struct TokenData {
+    access_token: String,
+    expires_in: Option<u64>,
+    expires_at: Option<DateTime<Utc>>,
+    token_type: Option<String>,
+    scope: Option<String>,
+    id_token: Option<String>,
+    provider: Option<String>,
+}
+
+impl TokenData {
+    /// Convert into a [`Token`], resolving a relative `expires_in` from `now`
+    pub(crate) fn into_token(self, now: DateTime<Utc>) -> Result<Token, &'static str> {
+        let expires_at = match (self.expires_at, self.expires_in) {
+            (Some(expires_at), _) => expires_at,
+            (None, Some(seconds)) => now + Duration::from_secs(seconds),
+            (None, None) => return Err("missing field `expires_in` or `expires_at`"),
+        };
+
+        Ok(Token {
+            access_token: self.access_token,
+            expires_at,
+            token_type: self.token_type,
+            scope: self.scope,
+            id_token: self.id_token,
+            provider: self.provider,
+        })
+    }
+}
+
+impl TryFrom<TokenData> for Token {
+    type Error = &'static str;
+
+    fn try_from(data: TokenData) -> Result<Self, Self::Error> {
+        data.into_token(Utc::now())
+    }
+}
+
 #[cfg(any(feature = "ring", feature = "aws-lc-rs"))]
 pub use self::sign::Signer;
 
@@ -275,19 +716,13 @@ mod sign {
     }
 }
 
-fn deserialize_time<'de, D>(deserializer: D) -> Result<DateTime<Utc>, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let seconds_from_now: u64 = Deserialize::deserialize(deserializer)?;
-    Ok(Utc::now() + Duration::from_secs(seconds_from_now))
-}
-
 #[cfg(any(feature = "ring", feature = "aws-lc-rs"))]
 #[derive(Deserialize)]
 pub(crate) struct ServiceAccountKey {
     /// project_id
     pub(crate) project_id: Option<Arc<str>>,
+    /// quota_project_id
+    pub(crate) quota_project_id: Option<Arc<str>>,
     /// private_key
     pub(crate) private_key: String,
     /// client_email
@@ -299,7 +734,7 @@ pub(crate) struct ServiceAccountKey {
 #[cfg(any(feature = "ring", feature = "aws-lc-rs"))]
 impl ServiceAccountKey {
     pub(crate) fn from_env() -> Result<Option<Self>, Error> {
-        env::var_os("GOOGLE_APPLICATION_CREDENTIALS")
+        env::var_os(ENV_CREDENTIALS)
             .map(|path| {
                 debug!(
                     ?path,
@@ -338,8 +773,12 @@ impl fmt::Debug for ServiceAccountKey {
     }
 }
 
+/// Refresh token credentials for an authorized user
+///
+/// As written by `gcloud auth application-default login` to
+/// `application_default_credentials.json`.
 #[derive(Deserialize)]
-pub(crate) struct AuthorizedUserRefreshToken {
+pub struct AuthorizedUserRefreshToken {
     /// Client id
     pub(crate) client_id: String,
     /// Client secret
@@ -351,12 +790,27 @@ pub(crate) struct AuthorizedUserRefreshToken {
 }
 
 impl AuthorizedUserRefreshToken {
-    pub(crate) fn from_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+    /// Read user credentials from the given JSON file
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Error> {
         let file = File::open(path.as_ref())
             .map_err(|err| Error::Io("failed to open application credentials file", err))?;
         serde_json::from_reader(file)
             .map_err(|err| Error::Json("failed to deserialize ApplicationCredentials", err))
     }
+
+    /// The quota project ID as found in the credentials
+    pub fn quota_project_id(&self) -> Option<&str> {
+        self.quota_project_id.as_deref()
+    }
+}
+
+impl FromStr for AuthorizedUserRefreshToken {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_str(s)
+            .map_err(|err| Error::Json("failed to deserialize ApplicationCredentials", err))
+    }
 }
 
 impl fmt::Debug for AuthorizedUserRefreshToken {
@@ -368,8 +822,18 @@ impl fmt::Debug for AuthorizedUserRefreshToken {
     }
 }
 
-/// How many times to attempt to fetch a token from the set credentials token endpoint.
-const RETRY_COUNT: u8 = 5;
+/// A pseudo-random number in the range 0.0 to 1.0, used to add jitter to delays
+pub(crate) fn random_fraction() -> f64 {
+    // Every `RandomState` is seeded with different keys, so this is good enough for jitter
+    let hash = RandomState::new().build_hasher().finish();
+    (hash >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Margin before the actual expiry at which a token is considered expired
+pub(crate) const EXPIRY_MARGIN: Duration = Duration::from_secs(20);
+
+#[cfg(any(feature = "ring", feature = "aws-lc-rs"))]
+pub(crate) const ENV_CREDENTIALS: &str = "GOOGLE_APPLICATION_CREDENTIALS";
 
 #[cfg(test)]
 mod tests {
@@ -378,14 +842,173 @@ mod tests {
     #[test]
     fn test_deserialize_with_time() {
         let s = r#"{"access_token":"abc123","expires_in":100}"#;
-        let token: Token = serde_json::from_str(s).unwrap();
-        let expires = Utc::now() + Duration::from_secs(100);
+        let now = Utc::now();
+        let token = serde_json::from_str::<TokenData>(s)
+            .unwrap()
+            .into_token(now)
+            .unwrap();
 
         assert_eq!(token.as_str(), "abc123");
+        assert_eq!(token.expires_at(), now + Duration::from_secs(100));
+        assert!(!token.expires_within(Duration::from_secs(99), now));
+        assert!(token.expires_within(Duration::from_secs(100), now));
+    }
+
+    #[test]
+    fn test_response_error() {
+        let body = br#"{"error":"invalid_grant","error_description":"Invalid JWT Signature."}"#;
+        let err = ResponseError::new(
+            "CustomServiceAccount",
+            StatusCode::BAD_REQUEST,
+            OAuthError::from_slice(body),
+            None,
+        );
+
+        let oauth = err.oauth_error().unwrap();
+        assert_eq!(oauth.error, "invalid_grant");
+        assert_eq!(
+            oauth.error_description.as_deref(),
+            Some("Invalid JWT Signature.")
+        );
+        assert!(err.is_invalid_credentials());
+        assert!(!err.is_retryable());
+        assert_eq!(
+            err.to_string(),
+            "CustomServiceAccount token request failed with status 400 Bad Request: \
+             invalid_grant (Invalid JWT Signature.)"
+        );
+
+        let body = br#"{"error":{"code":503,"message":"Unavailable","status":"UNAVAILABLE"}}"#;
+        let err = Error::Response(ResponseError::new(
+            "MetadataServiceAccount",
+            StatusCode::SERVICE_UNAVAILABLE,
+            OAuthError::from_slice(body),
+            None,
+        ));
+        assert!(err.is_retryable());
+        assert!(!err.is_invalid_credentials());
+        let Error::Response(response) = &err else {
+            unreachable!()
+        };
+        assert_eq!(response.oauth_error().unwrap().error, "UNAVAILABLE");
+        assert!(OAuthError::from_slice(b"<html>").is_none());
+    }
+
+    #[cfg(any(feature = "ring", feature = "aws-lc-rs"))]
+    #[tokio::test]
+    async fn test_request_timeout() {
+        // Accept connections, but never respond
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut connections = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                connections.push(stream);
+            }
+        });
+
+        let client = HttpClient::builder()
+            .with_request_timeout(Some(Duration::from_millis(50)))
+            .with_retry_policy(RetryPolicy::never())
+            .build()
+            .unwrap();
+        let request = || {
+            Request::get(format!("http://{addr}/token"))
+                .body(Bytes::new())
+                .unwrap()
+        };
 
-        // Testing time is always racy, give it 1s leeway.
-        let expires_at = token.expires_at();
-        assert!(expires_at < expires + Duration::from_secs(1));
-        assert!(expires_at > expires - Duration::from_secs(1));
+        let err = client
+            .token(&request, "test", &crate::SystemClock)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Timeout(_)), "{err:?}");
+        assert!(err.is_retryable());
+
+        // The overall deadline applies across retries
+        let client = HttpClient::builder()
+            .with_request_timeout(Some(Duration::from_millis(50)))
+            .with_timeout(Some(Duration::from_millis(200)))
+            .with_retry_policy(RetryPolicy::default().with_max_attempts(100))
+            .build()
+            .unwrap();
+        let err = client
+            .token(&request, "test", &crate::SystemClock)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "token request deadline exceeded");
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = DateTime::parse_from_rfc3339("2015-10-21T07:28:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:30 GMT", now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:27:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[test]
+    fn test_token_round_trip() {
+        let s = r#"{
+          "access_token": "abc123",
+          "expires_in": 3600,
+          "token_type": "Bearer",
+          "scope": "https://www.googleapis.com/auth/cloud-platform openid",
+          "id_token": "header.payload.signature"
+        }"#;
+
+        let token = serde_json::from_str::<Token>(s)
+            .unwrap()
+            .with_provider("CustomServiceAccount");
+        assert_eq!(token.token_type(), Some("Bearer"));
+        assert_eq!(
+            token.scopes().collect::<Vec<_>>(),
+            ["https://www.googleapis.com/auth/cloud-platform", "openid"]
+        );
+        assert_eq!(token.id_token(), Some("header.payload.signature"));
+        assert!(!format!("{token:?}").contains("header.payload.signature"));
+
+        let json = serde_json::to_value(&token).unwrap();
+        assert!(json.get("expires_in").is_none());
+        assert_eq!(json["provider"], "CustomServiceAccount");
+
+        let restored = serde_json::from_value::<Token>(json).unwrap();
+        assert_eq!(restored.as_str(), "abc123");
+        assert_eq!(restored.expires_at(), token.expires_at());
+        assert_eq!(restored.scope(), token.scope());
+        assert_eq!(restored.id_token(), token.id_token());
+        assert_eq!(restored.provider(), Some("CustomServiceAccount"));
+
+        let s = r#"{"access_token":"abc123"}"#;
+        assert!(serde_json::from_str::<Token>(s).is_err());
+    }
+
+    #[test]
+    fn test_user_credentials_from_str() {
+        let s = r#"{
+          "account": "",
+          "client_id": "123.apps.googleusercontent.com",
+          "client_secret": "secret",
+          "quota_project_id": "my-project",
+          "refresh_token": "refresh",
+          "type": "authorized_user",
+          "universe_domain": "googleapis.com"
+        }"#;
+
+        let credentials = AuthorizedUserRefreshToken::from_str(s).unwrap();
+        assert_eq!(credentials.client_id, "123.apps.googleusercontent.com");
+        assert_eq!(credentials.quota_project_id(), Some("my-project"));
     }
 }